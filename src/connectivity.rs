@@ -0,0 +1,304 @@
+//! Which nodes can reach which. Many onion failures are one node not being
+//! able to reach another rather than a dead node, so every link between a
+//! sample of nodes is tested on its own.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    loki::{Network, ServiceNode},
+    node_pool::NodePool,
+    triage,
+};
+
+/// How many links to test at once
+const N_PARALLEL: usize = 20;
+
+/// Requests from one node to another
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Link {
+    /// Ed25519 keys
+    pub from: String,
+    pub to: String,
+    pub attempts: u32,
+    pub successes: u32,
+}
+
+impl Link {
+    /// At least one request got through
+    pub fn works(&self) -> bool {
+        self.successes > 0
+    }
+}
+
+/// How many of the links from and to a node work
+#[derive(Debug, Clone, Default)]
+pub struct NodeConnectivity {
+    pub pubkey: String,
+    /// (working, tested)
+    pub outgoing: (u32, u32),
+    pub incoming: (u32, u32),
+}
+
+impl NodeConnectivity {
+    /// Some of the node's links work, but not all of them
+    pub fn is_partial(&self) -> bool {
+        let working = self.outgoing.0 + self.incoming.0;
+        let tested = self.outgoing.1 + self.incoming.1;
+
+        working > 0 && working < tested
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ConnectivityMatrix {
+    /// Unix time in seconds
+    pub measured_at: u64,
+    /// Ed25519 keys of the sampled nodes
+    pub nodes: Vec<String>,
+    /// Both directions between every two sampled nodes
+    pub links: Vec<Link>,
+}
+
+impl ConnectivityMatrix {
+    /// Test the links between `n_nodes` nodes from the pool, `attempts` times each
+    pub async fn measure(
+        node_pool: &mut NodePool,
+        n_nodes: usize,
+        attempts: u32,
+        net: &Network,
+    ) -> Self {
+        let nodes = node_pool.get_random_nodes(n_nodes);
+
+        ConnectivityMatrix::measure_nodes(&nodes, attempts, net).await
+    }
+
+    pub async fn measure_nodes(nodes: &[ServiceNode], attempts: u32, net: &Network) -> Self {
+        let pairs: Vec<(&ServiceNode, &ServiceNode)> = nodes
+            .iter()
+            .flat_map(|from| nodes.iter().map(move |to| (from, to)))
+            .filter(|(from, to)| from.pubkey_ed25519 != to.pubkey_ed25519)
+            .collect();
+
+        let requests = pairs
+            .iter()
+            .flat_map(|pair| std::iter::repeat_n(pair, attempts as usize))
+            .map(|(from, to)| async move {
+                let res = triage::probe_link(from, to, net).await;
+                (from, to, res.is_ok())
+            });
+
+        let results: Vec<_> = stream::iter(requests)
+            .buffer_unordered(N_PARALLEL)
+            .collect()
+            .await;
+
+        let mut counts = HashMap::<(&str, &str), u32>::new();
+
+        for (from, to, success) in results {
+            let entry = counts
+                .entry((&from.pubkey_ed25519, &to.pubkey_ed25519))
+                .or_insert(0);
+
+            *entry += success as u32;
+        }
+
+        let links = pairs
+            .iter()
+            .map(|(from, to)| Link {
+                from: from.pubkey_ed25519.clone(),
+                to: to.pubkey_ed25519.clone(),
+                attempts,
+                successes: counts[&(from.pubkey_ed25519.as_str(), to.pubkey_ed25519.as_str())],
+            })
+            .collect();
+
+        let measured_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Could not get UNIX time")
+            .as_secs();
+
+        ConnectivityMatrix {
+            measured_at,
+            nodes: nodes.iter().map(|n| n.pubkey_ed25519.clone()).collect(),
+            links,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let data = serde_json::to_string_pretty(self).expect("Could not serialize matrix");
+
+        std::fs::write(path, data)
+            .map_err(|err| format!("Could not write {}: {}", path.display(), err))
+    }
+
+    fn link(&self, from: &str, to: &str) -> Option<&Link> {
+        self.links.iter().find(|l| l.from == from && l.to == to)
+    }
+
+    /// Links that work while the link back doesn't, as (working, broken)
+    pub fn asymmetric(&self) -> Vec<(&Link, &Link)> {
+        self.links
+            .iter()
+            .filter(|l| l.works())
+            .filter_map(|l| {
+                self.link(&l.to, &l.from)
+                    .filter(|back| !back.works())
+                    .map(|back| (l, back))
+            })
+            .collect()
+    }
+
+    /// Sampled nodes without a single working link
+    fn unreachable(&self) -> HashSet<&str> {
+        self.nodes
+            .iter()
+            .map(|node| node.as_str())
+            .filter(|node| {
+                !self
+                    .links
+                    .iter()
+                    .any(|l| l.works() && (l.from == *node || l.to == *node))
+            })
+            .collect()
+    }
+
+    /// Every sampled node, in the order they were sampled. Links to unreachable
+    /// nodes only count for the unreachable node, so that one node being down
+    /// doesn't make everyone else look partially connected.
+    pub fn node_connectivity(&self) -> Vec<NodeConnectivity> {
+        let unreachable = self.unreachable();
+
+        self.nodes
+            .iter()
+            .map(|node| {
+                let mut res = NodeConnectivity {
+                    pubkey: node.clone(),
+                    ..Default::default()
+                };
+
+                let counts = |link: &Link| {
+                    unreachable.contains(node.as_str())
+                        || !(unreachable.contains(link.from.as_str())
+                            || unreachable.contains(link.to.as_str()))
+                };
+
+                for link in self.links.iter().filter(|l| counts(l)) {
+                    if &link.from == node {
+                        res.outgoing.0 += link.works() as u32;
+                        res.outgoing.1 += 1;
+                    }
+
+                    if &link.to == node {
+                        res.incoming.0 += link.works() as u32;
+                        res.incoming.1 += 1;
+                    }
+                }
+
+                res
+            })
+            .collect()
+    }
+
+    pub fn print(&self) {
+        let working = self.links.iter().filter(|l| l.works()).count();
+
+        println!(
+            "Links working: {}/{} between {} nodes",
+            working,
+            self.links.len(),
+            self.nodes.len()
+        );
+
+        for (there, back) in self.asymmetric() {
+            println!(
+                "Asymmetric: {} -> {} works ({}/{}), back fails ({}/{})",
+                there.from,
+                there.to,
+                there.successes,
+                there.attempts,
+                back.successes,
+                back.attempts
+            );
+        }
+
+        for node in self.node_connectivity() {
+            let status = if node.is_partial() {
+                "partial"
+            } else if node.outgoing.0 + node.incoming.0 == 0 {
+                "unreachable"
+            } else {
+                continue;
+            };
+
+            println!(
+                "{} {}: outgoing {}/{}, incoming {}/{}",
+                status,
+                node.pubkey,
+                node.outgoing.0,
+                node.outgoing.1,
+                node.incoming.0,
+                node.incoming.1
+            );
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_connectivity_matrix() {
+    use crate::{
+        fake_net::{FakeNetwork, Fault},
+        loki::LOCAL_NET,
+    };
+
+    let net = FakeNetwork::start(1, 4);
+
+    let nodes = net.service_nodes();
+
+    // Node 0 can't reach node 1, while node 1 reaches node 0 just fine
+    net.nodes()[0].set_faults(vec![Fault::RefuseRelayTo(nodes[1].pubkey_ed25519.clone())]);
+
+    // Node 3 is down
+    net.nodes()[3].set_faults(vec![Fault::DropConnection]);
+
+    let matrix = ConnectivityMatrix::measure_nodes(&nodes, 1, &LOCAL_NET).await;
+
+    assert_eq!(matrix.links.len(), 12);
+
+    let asymmetric = matrix.asymmetric();
+
+    assert_eq!(asymmetric.len(), 1);
+    assert_eq!(asymmetric[0].0.from, nodes[1].pubkey_ed25519);
+    assert_eq!(asymmetric[0].1.from, nodes[0].pubkey_ed25519);
+
+    let connectivity = matrix.node_connectivity();
+
+    // Node 3 being down doesn't count against the others
+    assert!(connectivity[0].is_partial());
+    assert!(connectivity[1].is_partial());
+    assert!(!connectivity[2].is_partial());
+    assert_eq!(connectivity[0].outgoing, (1, 2));
+    assert_eq!(connectivity[1].incoming, (1, 2));
+    assert_eq!(connectivity[2].outgoing, (2, 2));
+    assert_eq!(connectivity[3].outgoing, (0, 3));
+    assert_eq!(connectivity[3].incoming, (0, 3));
+    assert!(!connectivity[3].is_partial());
+
+    let path = std::env::temp_dir().join(format!("connectivity_{}.json", std::process::id()));
+
+    matrix.save(&path).unwrap();
+
+    let loaded: ConnectivityMatrix =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.nodes, matrix.nodes);
+    assert_eq!(loaded.asymmetric().len(), 1);
+}
@@ -1,9 +1,55 @@
+use std::{fmt, str::FromStr};
+
 use crate::onions::{HasX25519, NextHop};
 use ring::{agreement, hmac, rand};
 
 const NONCE_LENGTH: usize = 12;
 
-pub fn encrypt_gcm(target: &NextHop, plaintext: &[u8]) -> (Vec<u8>, Vec<u8>, agreement::PublicKey) {
+const XCHACHA20_NONCE_LENGTH: usize = 24;
+
+/// Cipher for one onion layer, sent to the hop as `enc_type`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EncryptionType {
+    /// AES-256-GCM with a key derived through the "LOKI" HMAC
+    #[default]
+    AesGcm,
+    /// XChaCha20-Poly1305 with a key derived through blake2b
+    XChaCha20,
+}
+
+impl EncryptionType {
+    /// The name service nodes expect in `enc_type`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EncryptionType::AesGcm => "aes-gcm",
+            EncryptionType::XChaCha20 => "xchacha20",
+        }
+    }
+}
+
+impl FromStr for EncryptionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-gcm" | "gcm" => Ok(EncryptionType::AesGcm),
+            "xchacha20" | "xchacha20-poly1305" => Ok(EncryptionType::XChaCha20),
+            _ => Err(format!("Unknown encryption type: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+pub fn encrypt(
+    target: &NextHop,
+    plaintext: &[u8],
+    enc_type: EncryptionType,
+) -> (Vec<u8>, Vec<u8>, agreement::PublicKey) {
     // TODO: only initialize once
     let rng = rand::SystemRandom::new();
 
@@ -27,7 +73,7 @@ pub fn encrypt_gcm(target: &NextHop, plaintext: &[u8]) -> (Vec<u8>, Vec<u8>, agr
         eprintln!("invalid length for peer target key: {}", target_key.len());
     }
 
-    let peer_pk = agreement::UnparsedPublicKey::new(&agreement::X25519, peer_pk_bytes);
+    let peer_pk = agreement::UnparsedPublicKey::new(&agreement::X25519, &peer_pk_bytes);
 
     // Note that this consumes our ephemeral key, we won't be able to reuse it.
     let shared_key = agreement::agree_ephemeral(
@@ -51,18 +97,175 @@ pub fn encrypt_gcm(target: &NextHop, plaintext: &[u8]) -> (Vec<u8>, Vec<u8>, agr
         }
     };
 
+    let symm_key = match enc_type {
+        EncryptionType::AesGcm => derive_from_shared_key(&shared_key),
+        EncryptionType::XChaCha20 => {
+            derive_xchacha20_key(&shared_key, pubkey.as_ref(), &peer_pk_bytes)
+        }
+    };
+
+    let iv_and_ciphertext = encrypt_bytes(enc_type, plaintext, &symm_key);
+
+    return (iv_and_ciphertext, symm_key, pubkey);
+}
+
+fn derive_from_shared_key(shared_key: &[u8]) -> Vec<u8> {
     // Derive key with HKDF
     let salt = "LOKI";
 
     let s_key = hmac::Key::new(hmac::HMAC_SHA256, salt.as_bytes());
-    let symm_key = hmac::sign(&s_key, &shared_key).as_ref().to_vec();
+    hmac::sign(&s_key, shared_key).as_ref().to_vec()
+}
 
-    let iv_and_ciphertext = aes_gcm_encrypt(&plaintext, &symm_key);
+/// Same as the storage server: blake2b(shared key || sender pubkey || receiver pubkey)
+fn derive_xchacha20_key(
+    shared_key: &[u8],
+    sender_pubkey: &[u8],
+    receiver_pubkey: &[u8],
+) -> Vec<u8> {
+    use blake2::{digest::consts::U32, Blake2b, Digest};
 
-    return (iv_and_ciphertext, symm_key, pubkey);
+    let mut hasher = Blake2b::<U32>::new();
+
+    hasher.update(shared_key);
+    hasher.update(sender_pubkey);
+    hasher.update(receiver_pubkey);
+
+    hasher.finalize().to_vec()
+}
+
+/// Generate a long-term x25519 keypair (secret key, public key), e.g. for a local node
+pub fn gen_static_keypair() -> ([u8; 32], [u8; 32]) {
+    use ring::rand::SecureRandom;
+
+    let rng = rand::SystemRandom::new();
+
+    let mut seckey = [0u8; 32];
+    rng.fill(&mut seckey).expect("Failed to generate seckey");
+
+    let secret = x25519_dalek::StaticSecret::from(seckey);
+    let pubkey = x25519_dalek::PublicKey::from(&secret);
+
+    (secret.to_bytes(), pubkey.to_bytes())
+}
+
+/// The receiving side of `encrypt`: derive the same symmetric key
+/// from our static secret key and the sender's ephemeral public key
+pub fn derive_symmetric_key(
+    seckey: &[u8; 32],
+    ephemeral_pubkey: &[u8],
+    enc_type: EncryptionType,
+) -> Option<Vec<u8>> {
+    if ephemeral_pubkey.len() != 32 {
+        eprintln!(
+            "invalid length for ephemeral key: {}",
+            ephemeral_pubkey.len()
+        );
+        return None;
+    }
+
+    let mut pubkey = [0u8; 32];
+    pubkey.copy_from_slice(ephemeral_pubkey);
+
+    let secret = x25519_dalek::StaticSecret::from(*seckey);
+    let shared_key = secret.diffie_hellman(&x25519_dalek::PublicKey::from(pubkey));
+
+    let key = match enc_type {
+        EncryptionType::AesGcm => derive_from_shared_key(shared_key.as_bytes()),
+        EncryptionType::XChaCha20 => {
+            let own_pubkey = x25519_dalek::PublicKey::from(&secret);
+            derive_xchacha20_key(shared_key.as_bytes(), &pubkey, own_pubkey.as_bytes())
+        }
+    };
+
+    Some(key)
+}
+
+/// Encrypt with `enc_type`, prepending the nonce
+pub fn encrypt_bytes(enc_type: EncryptionType, plaintext: &[u8], key: &[u8]) -> Vec<u8> {
+    match enc_type {
+        EncryptionType::AesGcm => aes_gcm_encrypt(plaintext, key),
+        EncryptionType::XChaCha20 => xchacha20_encrypt(plaintext, key),
+    }
 }
 
-pub fn aes_gcm_decrypt(iv_and_ciphertext: String, key: &Vec<u8>) -> Option<String> {
+/// Reverse of `encrypt_bytes`
+pub fn decrypt_bytes(enc_type: EncryptionType, data: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    match enc_type {
+        EncryptionType::AesGcm => aes_gcm_decrypt_bytes(data, key),
+        EncryptionType::XChaCha20 => xchacha20_decrypt_bytes(data, key),
+    }
+}
+
+/// Same as `decrypt_bytes`, but for base64 encoded data
+pub fn decrypt(enc_type: EncryptionType, data: String, key: &[u8]) -> Option<String> {
+    if let EncryptionType::AesGcm = enc_type {
+        return aes_gcm_decrypt(data, key);
+    }
+
+    let data = match base64::decode(&data) {
+        Ok(v) => v,
+        Err(_) => {
+            eprintln!("Could not decode ciphertext from base64");
+            eprintln!(" ciphertext: <{}>", data);
+            return None;
+        }
+    };
+
+    let plaintext = decrypt_bytes(enc_type, &data, key)?;
+
+    Some(String::from_utf8_lossy(&plaintext).to_string())
+}
+
+pub fn xchacha20_encrypt(plaintext: &[u8], key: &[u8]) -> Vec<u8> {
+    use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305, XNonce};
+    use ring::rand::SecureRandom;
+
+    let rng = rand::SystemRandom::new();
+
+    let mut nonce = [0u8; XCHACHA20_NONCE_LENGTH];
+    rng.fill(&mut nonce).expect("Failed to generate nonce");
+
+    let cipher = XChaCha20Poly1305::new_from_slice(key).expect("Invalid key length");
+
+    // tag comes after ciphertext
+    let mut ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .expect("Failed to encrypt");
+
+    let mut nonce_and_ciphertext = nonce.to_vec();
+
+    nonce_and_ciphertext.append(&mut ciphertext);
+
+    nonce_and_ciphertext
+}
+
+pub fn xchacha20_decrypt_bytes(nonce_and_ciphertext: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305, XNonce};
+
+    const TAG_LENGTH: usize = 16;
+
+    if nonce_and_ciphertext.len() < XCHACHA20_NONCE_LENGTH + TAG_LENGTH {
+        eprintln!(
+            "nonce_and_ciphertext too short, len: {}",
+            nonce_and_ciphertext.len()
+        );
+        return None;
+    }
+
+    let (nonce, ciphertext) = nonce_and_ciphertext.split_at(XCHACHA20_NONCE_LENGTH);
+
+    let cipher = XChaCha20Poly1305::new_from_slice(key).ok()?;
+
+    cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| {
+            eprintln!("Could not decrypt ciphertext, len: {}", ciphertext.len());
+        })
+        .ok()
+}
+
+pub fn aes_gcm_decrypt(iv_and_ciphertext: String, key: &[u8]) -> Option<String> {
     let iv_and_ciphertext = match base64::decode(&iv_and_ciphertext) {
         Ok(v) => v,
         Err(e) => {
@@ -72,11 +275,23 @@ pub fn aes_gcm_decrypt(iv_and_ciphertext: String, key: &Vec<u8>) -> Option<Strin
         }
     };
 
+    let plaintext = aes_gcm_decrypt_bytes(&iv_and_ciphertext, key)?;
+
+    let plaintext = String::from_utf8_lossy(&plaintext).to_string();
+
+    Some(plaintext)
+}
+
+/// Same as `aes_gcm_decrypt`, but for binary (not base64 encoded) data
+pub fn aes_gcm_decrypt_bytes(iv_and_ciphertext: &[u8], key: &[u8]) -> Option<Vec<u8>> {
     const TAG_LENGTH: usize = 16;
 
-        // iv_and_ciphertext must be at least NONCE_LENGTH + TAG_LENGTH long
+    // iv_and_ciphertext must be at least NONCE_LENGTH + TAG_LENGTH long
     if iv_and_ciphertext.len() < NONCE_LENGTH + TAG_LENGTH {
-        eprintln!("iv_and_ciphertext too short, len: {}", iv_and_ciphertext.len());
+        eprintln!(
+            "iv_and_ciphertext too short, len: {}",
+            iv_and_ciphertext.len()
+        );
         return None;
     }
 
@@ -107,8 +322,6 @@ pub fn aes_gcm_decrypt(iv_and_ciphertext: String, key: &Vec<u8>) -> Option<Strin
     })
     .ok()?;
 
-    let plaintext = String::from_utf8_lossy(&plaintext).to_string();
-
     Some(plaintext)
 }
 
@@ -127,7 +340,7 @@ pub fn gen_keypair() -> (
     (seckey, pubkey)
 }
 
-fn aes_gcm_encrypt(plaintext: &[u8], shared_key: &Vec<u8>) -> Vec<u8> {
+pub fn aes_gcm_encrypt(plaintext: &[u8], shared_key: &[u8]) -> Vec<u8> {
     use openssl::symm::{encrypt_aead, Cipher};
     use ring::rand::SecureRandom;
 
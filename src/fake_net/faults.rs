@@ -0,0 +1,60 @@
+use std::{str::FromStr, time::Duration};
+
+/// Misbehaviour injected into a fake node, so that failure attribution
+/// can be checked against nodes that are known to be bad
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    /// Stop listening, so connections to the node are refused as if it went offline
+    DropConnection,
+    /// Wait before handling every request
+    Delay(Duration),
+    /// Respond to every request with this status (e.g. 502, 503, 421)
+    Status(u16),
+    /// Tamper with the ciphertext we relay or respond with
+    CorruptCiphertext,
+    /// Pretend the node with this ed25519 key can't be reached
+    RefuseRelayTo(String),
+    /// Swap the last node of the list served through `oxend_request` for a made-up one
+    LieAboutNodes,
+    /// List our own swarm as the swarm of every key
+    WrongSwarm,
+}
+
+impl FromStr for Fault {
+    type Err = String;
+
+    /// One of `drop`, `delay=<ms>`, `status=<code>`, `corrupt`, `refuse=<ed25519 key>`, `lie`,
+    /// `wrong_swarm`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+
+        let kind = parts.next().unwrap_or_default();
+        let arg = parts.next();
+
+        let parse_arg = || -> Result<u64, String> {
+            arg.and_then(|arg| arg.parse().ok())
+                .ok_or(format!("Expected a number argument for `{}`", kind))
+        };
+
+        match kind {
+            "drop" => Ok(Fault::DropConnection),
+            "delay" => Ok(Fault::Delay(Duration::from_millis(parse_arg()?))),
+            "status" => Ok(Fault::Status(parse_arg()? as u16)),
+            "corrupt" => Ok(Fault::CorruptCiphertext),
+            "lie" => Ok(Fault::LieAboutNodes),
+            "wrong_swarm" => Ok(Fault::WrongSwarm),
+            "refuse" => match arg {
+                Some(key) => Ok(Fault::RefuseRelayTo(key.to_owned())),
+                None => Err("Expected a node's ed25519 key for `refuse`".to_owned()),
+            },
+            _ => Err(format!("Unknown fault: {}", s)),
+        }
+    }
+}
+
+/// Flip a bit in the last byte, which for AES-GCM is part of the tag
+pub(super) fn corrupt(data: &mut [u8]) {
+    if let Some(last) = data.last_mut() {
+        *last ^= 1;
+    }
+}
@@ -0,0 +1,660 @@
+//! Service node stand-ins running in this process, so that onion
+//! requests can be tested end to end without network access
+
+use std::{
+    io::Read,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use parking_lot::RwLock;
+use rand::{prelude::SliceRandom, RngCore};
+
+use crate::{loki::ServiceNode, FakeNetOptions};
+
+#[cfg(test)]
+use crate::node_pool::NodePool;
+
+mod faults;
+mod node;
+mod seed;
+
+pub use faults::Fault;
+pub use node::FakeNode;
+pub use seed::{FakeSeed, SeedFixture};
+
+/// All nodes of a fake network, used by nodes to find the next hop
+type Directory = Arc<RwLock<Vec<ServiceNode>>>;
+
+pub struct FakeNetwork {
+    nodes: Vec<FakeNode>,
+    directory: Directory,
+}
+
+impl FakeNetwork {
+    /// Start `n_swarms` swarms of `swarm_size` nodes each
+    pub fn start(n_swarms: usize, swarm_size: usize) -> Self {
+        assert!(n_swarms > 0, "A fake network needs at least one swarm");
+        assert!(swarm_size > 0, "Fake swarms need at least one node");
+
+        let mut net = FakeNetwork {
+            nodes: vec![],
+            directory: Arc::new(RwLock::new(vec![])),
+        };
+
+        // Spread swarm ids evenly over the swarm space
+        let step = u64::MAX / n_swarms as u64;
+
+        for swarm in 0..n_swarms {
+            for _ in 0..swarm_size {
+                net.add_node(step * swarm as u64);
+            }
+        }
+
+        net
+    }
+
+    /// Start one more node in swarm `swarm_id`
+    pub fn add_node(&mut self, swarm_id: u64) -> ServiceNode {
+        let mut rng = rand::thread_rng();
+
+        let mut random_key = || {
+            let mut key = [0u8; 32];
+            rng.fill_bytes(&mut key);
+            hex::encode(key)
+        };
+
+        let idx = self.nodes.len();
+
+        let info = ServiceNode {
+            public_ip: "127.0.0.1".to_owned(),
+            storage_port: 0,
+            storage_lmq_port: 0,
+            service_node_pubkey: random_key(),
+            operator_address: format!("fake_operator_{}", idx),
+            pubkey_x25519: "".to_owned(),
+            pubkey_ed25519: random_key(),
+            swarm_id,
+            plain_http: true,
+        };
+
+        let node = FakeNode::start(info, self.directory.clone());
+
+        let info = node.info();
+
+        self.directory.write().push(info.clone());
+        self.nodes.push(node);
+
+        info
+    }
+
+    pub fn nodes(&self) -> &[FakeNode] {
+        &self.nodes
+    }
+
+    pub fn service_nodes(&self) -> Vec<ServiceNode> {
+        self.directory.read().clone()
+    }
+
+    #[cfg(test)]
+    pub fn node_pool(&self) -> NodePool {
+        NodePool::from_nodes(self.service_nodes())
+    }
+
+    pub fn fixture(&self) -> SeedFixture {
+        SeedFixture {
+            service_node_states: self.service_nodes(),
+        }
+    }
+}
+
+/// Serve a seed on `options.seed_port`, either for freshly started fake
+/// nodes or for the nodes listed in a fixture, until the process is killed
+pub async fn run(options: FakeNetOptions) {
+    let (_net, fixture) = match &options.fixture {
+        Some(path) => {
+            let fixture = SeedFixture::load(path).expect("Could not load fixture");
+            (None, fixture)
+        }
+        None => {
+            let net = FakeNetwork::start(options.swarms, options.swarm_size);
+
+            let mut rng = rand::thread_rng();
+
+            // Each fault goes to a different node, print them to know the ground truth
+            let faulty = net.nodes().choose_multiple(&mut rng, options.faults.len());
+
+            for (node, fault) in faulty.zip(options.faults.iter()) {
+                let info = node.info();
+                println!("{} ({}): {:?}", info, info.pubkey_ed25519, fault);
+                node.set_faults(vec![fault.clone()]);
+            }
+
+            let fixture = net.fixture();
+            (Some(net), fixture)
+        }
+    };
+
+    let count = fixture.service_node_states.len();
+
+    let seed = FakeSeed::start(options.seed_port, fixture).expect("Could not start the seed");
+
+    println!("Serving {} nodes from seed at {}", count, seed.url());
+
+    loop {
+        async_std::task::sleep(Duration::from_secs(60)).await;
+    }
+}
+
+/// A rouille server polled on its own thread until dropped
+struct LocalServer {
+    port: u16,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LocalServer {
+    fn start<F>(address: &str, handler: F) -> Result<Self, String>
+    where
+        F: Send + Sync + 'static + Fn(&rouille::Request) -> rouille::Response,
+    {
+        let server = rouille::Server::new(address, handler).map_err(|err| err.to_string())?;
+
+        let port = server.server_addr().port();
+
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let stop_clone = stop.clone();
+
+        let thread = std::thread::spawn(move || {
+            while !stop_clone.load(Ordering::Relaxed) {
+                server.poll();
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        Ok(LocalServer {
+            port,
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn read_body(req: &rouille::Request) -> Option<Vec<u8>> {
+    let mut body = vec![];
+
+    if let Some(mut data) = req.data() {
+        data.read_to_end(&mut body).ok()?;
+    }
+
+    Some(body)
+}
+
+#[tokio::test]
+async fn test_onion_through_fake_nodes() {
+    use crate::{loki, onions::NextHop, onions_core::OnionVersion, sn_api};
+    use serde_json::json;
+
+    let net = FakeNetwork::start(3, 3);
+
+    let mut node_pool = net.node_pool();
+
+    let pk = loki::PubKey::gen_random(&mut rand::thread_rng(), &loki::MAINNET).to_string();
+
+    let target = node_pool.get_random_nodes(1).pop().unwrap();
+
+    let expected = sn_api::get_swarm_for_pk(&target, &pk)
+        .await
+        .expect("Could not get swarm over clearnet");
+
+    assert_eq!(expected.len(), 3);
+
+    let payload = json!({
+        "method": "get_snodes_for_pubkey",
+        "params": {
+            "pubKey": &pk,
+        }
+    })
+    .to_string();
+
+    let path = node_pool.get_random_path();
+
+    let res = crate::onions::send_onion_req(
+        path,
+        NextHop::Node(target),
+        payload.as_bytes(),
+        0,
+        OnionVersion::V2,
+        Default::default(),
+    )
+    .await
+    .expect("Onion request failed");
+
+    let res: serde_json::Value = serde_json::from_str(&res).unwrap();
+
+    let snodes = res["snodes"].as_array().unwrap();
+
+    assert_eq!(snodes.len(), expected.len());
+    assert!(snodes.iter().all(|n| expected
+        .iter()
+        .any(|e| n["pubkey_ed25519"] == e.pubkey_ed25519)));
+}
+
+#[tokio::test]
+async fn test_onion_v4_through_fake_nodes() {
+    use crate::{
+        onions::{send_onion_req, NextHop, OnionErrorKind},
+        onions_core::OnionVersion,
+    };
+
+    let net = FakeNetwork::start(1, 4);
+
+    let nodes = net.service_nodes();
+
+    let path = [nodes[0].clone(), nodes[1].clone(), nodes[2].clone()];
+    let target = NextHop::Node(nodes[3].clone());
+
+    let payload = serde_json::json!({
+        "method": "get_snodes_for_pubkey",
+        "params": {"pubKey": nodes[0].pubkey_ed25519},
+    })
+    .to_string();
+
+    let res = send_onion_req(
+        path.clone(),
+        target.clone(),
+        payload.as_bytes(),
+        0,
+        OnionVersion::V4,
+        Default::default(),
+    )
+    .await
+    .expect("Onion request failed");
+
+    let res: serde_json::Value = serde_json::from_str(&res).unwrap();
+
+    assert_eq!(res["snodes"].as_array().unwrap().len(), 4);
+
+    net.nodes()[3].set_faults(vec![Fault::CorruptCiphertext]);
+
+    let res = send_onion_req(
+        path,
+        target,
+        payload.as_bytes(),
+        0,
+        OnionVersion::V4,
+        Default::default(),
+    )
+    .await;
+
+    assert_eq!(res.unwrap_err().kind, OnionErrorKind::Decryption);
+}
+
+#[tokio::test]
+async fn test_mixed_ciphers_through_fake_nodes() {
+    use crate::{
+        ecdh::EncryptionType::{AesGcm, XChaCha20},
+        onions::{send_onion_req, NextHop},
+        onions_core::OnionVersion,
+    };
+
+    let net = FakeNetwork::start(1, 4);
+
+    let nodes = net.service_nodes();
+
+    let path = [nodes[0].clone(), nodes[1].clone(), nodes[2].clone()];
+    let target = NextHop::Node(nodes[3].clone());
+
+    let payload = serde_json::json!({
+        "method": "get_snodes_for_pubkey",
+        "params": {"pubKey": nodes[0].pubkey_ed25519},
+    })
+    .to_string();
+
+    let combinations = [
+        [XChaCha20; 4],
+        [AesGcm, XChaCha20, AesGcm, XChaCha20],
+        [XChaCha20, AesGcm, XChaCha20, AesGcm],
+    ];
+
+    for enc_types in &combinations {
+        for version in &[OnionVersion::V2, OnionVersion::V4] {
+            let res = send_onion_req(
+                path.clone(),
+                target.clone(),
+                payload.as_bytes(),
+                0,
+                *version,
+                *enc_types,
+            )
+            .await;
+
+            assert!(res.is_ok(), "{} {:?}: {:?}", version, enc_types, res);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_node_pool_from_fake_seed() {
+    let net = FakeNetwork::start(2, 2);
+
+    let seed = FakeSeed::start(0, net.fixture()).unwrap();
+
+    let node_pool = NodePool::try_init(&seed.network())
+        .await
+        .expect("Could not get nodes from seed");
+
+    assert_eq!(node_pool.get_all_nodes().len(), 4);
+    assert_eq!(node_pool.swarm_count(), 2);
+    assert!(node_pool.get_all_nodes().iter().all(|n| n.plain_http));
+
+    seed.set_nodes(net.service_nodes()[..1].to_vec());
+
+    let nodes = crate::loki::get_n_service_nodes(0, &seed.network())
+        .await
+        .unwrap();
+
+    assert_eq!(nodes.len(), 1);
+
+    seed.set_available(false);
+
+    assert!(NodePool::try_init(&seed.network()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_pool_snapshot() {
+    use crate::onions::{NextHop, OnionPath};
+    use std::borrow::Cow;
+
+    let net = FakeNetwork::start(1, 4);
+
+    let seed = FakeSeed::start(0, net.fixture()).unwrap();
+
+    let path = std::env::temp_dir().join(format!("node_pool_{}.json", std::process::id()));
+
+    let network = crate::loki::Network {
+        snapshot_file: Some(Cow::Owned(path.to_str().unwrap().to_owned())),
+        ..seed.network()
+    };
+
+    let mut node_pool = NodePool::init(&network).await;
+
+    let [n1, n2, n3] = node_pool.get_random_path();
+
+    let guard = NextHop::Node(n1.clone());
+
+    let onion_path = OnionPath {
+        node_1: guard.clone(),
+        node_2: NextHop::Node(n2),
+        node_3: NextHop::Node(n3.clone()),
+        target: NextHop::Node(n3),
+    };
+
+    node_pool.report_failure(&onion_path, Some(&guard));
+    node_pool.save(&path).unwrap();
+
+    // The seed is down, so the nodes and their reputation come from the snapshot
+    seed.set_available(false);
+
+    let node_pool = NodePool::init(&network).await;
+
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(node_pool.get_all_nodes().len(), 4);
+
+    let (worst, reputation) = &node_pool.scoreboard()[0];
+
+    assert_eq!(worst.pubkey_ed25519, n1.pubkey_ed25519);
+    assert_eq!(reputation.failures, 1);
+}
+
+#[tokio::test]
+async fn test_refresh_from_nodes() {
+    let net = FakeNetwork::start(2, 3);
+
+    let liar = &net.nodes()[0];
+    liar.set_faults(vec![Fault::LieAboutNodes]);
+
+    let sources: Vec<ServiceNode> = net.nodes()[..3].iter().map(|n| n.info()).collect();
+
+    let network = crate::loki::LOCAL_NET;
+
+    // Only the liar knows the made-up node, and it leaves out a real one
+    let liar_nodes = crate::sn_api::get_service_nodes(&liar.info())
+        .await
+        .unwrap();
+    assert_eq!(liar_nodes.len(), 6);
+
+    let left_out = net.service_nodes().pop().unwrap();
+    assert!(liar_nodes
+        .iter()
+        .all(|n| n.pubkey_ed25519 != left_out.pubkey_ed25519));
+
+    let nodes = crate::node_pool::fetch_from_nodes(&sources, &network)
+        .await
+        .unwrap();
+
+    assert_eq!(nodes.len(), 6);
+    assert!(nodes.iter().all(|n| n.plain_http));
+    assert!(nodes
+        .iter()
+        .any(|n| n.pubkey_ed25519 == left_out.pubkey_ed25519));
+
+    let mut node_pool = NodePool::from_nodes(sources.clone());
+
+    node_pool.refresh_from_nodes(&network, 3).await.unwrap();
+    assert_eq!(node_pool.get_all_nodes().len(), 6);
+
+    // Not enough nodes to agree on anything
+    net.nodes()[1].set_faults(vec![Fault::DropConnection]);
+    net.nodes()[2].set_faults(vec![Fault::DropConnection]);
+
+    assert!(crate::node_pool::fetch_from_nodes(&sources, &network)
+        .await
+        .is_err());
+
+    let mut node_pool = NodePool::from_nodes(sources[1..].to_vec());
+
+    assert!(node_pool.refresh_from_nodes(&network, 2).await.is_err());
+}
+
+#[tokio::test]
+async fn test_network_file() {
+    let net = FakeNetwork::start(1, 3);
+
+    let mut fixture = net.fixture();
+    fixture.service_node_states[0].storage_port = 0;
+
+    let seed = FakeSeed::start(0, fixture.clone()).unwrap();
+
+    let blocked = fixture.service_node_states[1].pubkey_ed25519.clone();
+
+    // The first seed is down, so the second one has to be used
+    let network = serde_json::json!({
+        "name": "test",
+        "seed_urls": ["http://127.0.0.1:1/json_rpc", seed.url()],
+        "storage_port": 1234,
+        "blocklist": [blocked],
+    });
+
+    let path = std::env::temp_dir().join(format!("network_{}.json", std::process::id()));
+
+    std::fs::write(&path, network.to_string()).unwrap();
+
+    let network: crate::loki::Network = path.to_str().unwrap().parse().unwrap();
+
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(network.session_id_prefix, "");
+
+    let nodes = crate::loki::get_n_service_nodes(0, &network).await.unwrap();
+
+    assert_eq!(nodes.len(), 2);
+    assert!(nodes.iter().all(|n| n.pubkey_ed25519 != blocked));
+    // Missing ports are filled in from the network, fake nodes have no lmq port
+    assert_eq!(nodes[0].storage_port, 1234);
+    assert!(nodes.iter().all(|n| n.storage_lmq_port == 22020));
+}
+
+#[test]
+fn test_fixture_roundtrip() {
+    let fixture = FakeNetwork::start(1, 2).fixture();
+
+    let path = std::env::temp_dir().join(format!("fixture_{}.json", std::process::id()));
+
+    fixture.save(&path).unwrap();
+
+    let loaded = SeedFixture::load(&path).unwrap();
+
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.service_node_states.len(), 2);
+    assert_eq!(
+        loaded.service_node_states[1].pubkey_x25519,
+        fixture.service_node_states[1].pubkey_x25519
+    );
+}
+
+#[tokio::test]
+async fn test_injected_faults() {
+    use crate::{
+        onions::{send_onion_req, NextHop, OnionErrorKind},
+        onions_core::OnionVersion,
+    };
+
+    let net = FakeNetwork::start(1, 4);
+
+    let nodes: Vec<_> = net.service_nodes();
+
+    let path = [nodes[0].clone(), nodes[1].clone(), nodes[2].clone()];
+    let target = NextHop::Node(nodes[3].clone());
+
+    let payload = serde_json::json!({
+        "method": "get_snodes_for_pubkey",
+        "params": {"pubKey": nodes[0].pubkey_ed25519},
+    })
+    .to_string();
+
+    let status = |status| OnionErrorKind::GuardStatus {
+        status,
+        body: String::new(),
+    };
+
+    // Fault, the kind of error it should cause and which node (if any) gets the blame
+    let faults = vec![
+        (
+            0,
+            Fault::DropConnection,
+            OnionErrorKind::GuardTransport(String::new()),
+            Some(0),
+        ),
+        (1, Fault::Status(503), status(503), None),
+        (1, Fault::CorruptCiphertext, status(400), None),
+        (
+            3,
+            Fault::CorruptCiphertext,
+            OnionErrorKind::Decryption,
+            None,
+        ),
+        (
+            2,
+            Fault::RefuseRelayTo(nodes[3].pubkey_ed25519.clone()),
+            OnionErrorKind::NextNodeUnreachable(String::new()),
+            Some(3),
+        ),
+    ];
+
+    for (idx, fault, expected, culprit) in faults {
+        net.nodes()[idx].set_faults(vec![fault.clone()]);
+
+        let res = send_onion_req(
+            path.clone(),
+            target.clone(),
+            payload.as_bytes(),
+            0,
+            OnionVersion::V2,
+            Default::default(),
+        )
+        .await;
+
+        let err = res.expect_err("Expected the request to fail");
+
+        assert_eq!(
+            std::mem::discriminant(&err.kind),
+            std::mem::discriminant(&expected),
+            "{:?}: unexpected error: {}",
+            fault,
+            err
+        );
+
+        let culprit = culprit.map(|idx| nodes[idx].pubkey_ed25519.clone());
+
+        let blamed = err.culprit().map(|hop| match hop {
+            NextHop::Node(n) => n.pubkey_ed25519.clone(),
+            _ => panic!("Only nodes on the path"),
+        });
+
+        assert_eq!(blamed, culprit, "{:?}: blamed the wrong node", fault);
+
+        net.nodes()[idx].set_faults(vec![]);
+    }
+
+    // Errors reported by the target itself are the target's fault
+    let invalid_method = serde_json::json!({
+        "method": "no_such_method",
+        "params": {"pubKey": nodes[0].pubkey_ed25519},
+    })
+    .to_string();
+
+    let err = send_onion_req(
+        path.clone(),
+        target.clone(),
+        invalid_method.as_bytes(),
+        0,
+        OnionVersion::V2,
+        Default::default(),
+    )
+    .await
+    .expect_err("Expected the target to reject the request");
+
+    assert!(matches!(
+        err.kind,
+        OnionErrorKind::Target { status: 400, .. }
+    ));
+    assert_eq!(
+        err.culprit().map(|hop| hop.to_string()),
+        Some(target.to_string())
+    );
+
+    assert!(send_onion_req(
+        path,
+        target,
+        payload.as_bytes(),
+        0,
+        OnionVersion::V2,
+        Default::default()
+    )
+    .await
+    .is_ok());
+}
+
+#[test]
+#[should_panic(expected = "at least one swarm")]
+fn test_no_swarms() {
+    FakeNetwork::start(0, 3);
+}
@@ -0,0 +1,560 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha512};
+
+use crate::{
+    ecdh,
+    loki::ServiceNode,
+    onions_core::{
+        v2::{self, LayerDestination},
+        v4,
+    },
+    session_identity::verify_request,
+    swarm_mapping::swarm_for_pubkey,
+};
+
+use super::{
+    faults::{self, Fault},
+    read_body, Directory, LocalServer,
+};
+
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub hash: String,
+    pub data: String,
+    pub timestamp: u64,
+    pub ttl: u64,
+}
+
+struct NodeState {
+    /// Port is only known after the server is bound
+    info: RwLock<ServiceNode>,
+    seckey: [u8; 32],
+    directory: Directory,
+    /// pubkey -> messages, in the order they were stored
+    messages: Mutex<HashMap<String, Vec<StoredMessage>>>,
+    faults: RwLock<Vec<Fault>>,
+}
+
+/// A service node stand-in serving `onion_req/v2` and `storage_rpc/v1` over plain http.
+/// The server is stopped when this is dropped.
+pub struct FakeNode {
+    state: Arc<NodeState>,
+    /// Not running while the node is "offline"
+    server: Mutex<Option<LocalServer>>,
+}
+
+impl FakeNode {
+    /// Start listening on a random localhost port. The address and the x25519 key
+    /// in `info` are replaced with those of the new node.
+    pub fn start(mut info: ServiceNode, directory: Directory) -> Self {
+        let (seckey, pubkey) = ecdh::gen_static_keypair();
+
+        info.pubkey_x25519 = hex::encode(pubkey);
+        info.public_ip = "127.0.0.1".to_owned();
+        info.plain_http = true;
+
+        let state = Arc::new(NodeState {
+            info: RwLock::new(info),
+            seckey,
+            directory,
+            messages: Mutex::new(HashMap::new()),
+            faults: RwLock::new(vec![]),
+        });
+
+        let server = listen(&state, 0).expect("Could not start fake node");
+
+        state.info.write().storage_port = server.port;
+
+        FakeNode {
+            state,
+            server: Mutex::new(Some(server)),
+        }
+    }
+
+    pub fn info(&self) -> ServiceNode {
+        self.state.info.read().clone()
+    }
+
+    /// Replace the faults this node exhibits (an empty list makes it healthy again)
+    pub fn set_faults(&self, faults: Vec<Fault>) {
+        let offline = faults.contains(&Fault::DropConnection);
+
+        *self.state.faults.write() = faults;
+
+        let mut server = self.server.lock();
+
+        if offline {
+            *server = None;
+        } else if server.is_none() {
+            let port = self.state.info.read().storage_port;
+            *server = Some(listen(&self.state, port).expect("Could not restart fake node"));
+        }
+    }
+
+    /// Messages currently stored for `pk`
+    #[cfg(test)]
+    pub fn messages_for(&self, pk: &str) -> Vec<StoredMessage> {
+        self.state
+            .messages
+            .lock()
+            .get(pk)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+fn listen(state: &Arc<NodeState>, port: u16) -> Result<LocalServer, String> {
+    let state = state.clone();
+
+    LocalServer::start(&format!("127.0.0.1:{}", port), move |req| {
+        handle_request(&state, req)
+    })
+}
+
+fn handle_request(state: &NodeState, req: &rouille::Request) -> rouille::Response {
+    if req.method() != "POST" {
+        return rouille::Response::text("Only POST is supported").with_status_code(405);
+    }
+
+    let faults = state.faults.read().clone();
+
+    for fault in &faults {
+        match fault {
+            Fault::Delay(delay) => std::thread::sleep(*delay),
+            Fault::Status(status) => {
+                return rouille::Response::text("Injected fault").with_status_code(*status)
+            }
+            _ => {}
+        }
+    }
+
+    let body = match read_body(req) {
+        Some(body) => body,
+        None => return rouille::Response::text("Could not read body").with_status_code(400),
+    };
+
+    match req.url().as_str() {
+        "/onion_req/v2" => handle_onion_req(state, &faults, &body),
+        "/storage_rpc/v1" => {
+            let (status, body) = process_rpc(state, &body);
+            rouille::Response::text(body).with_status_code(status)
+        }
+        _ => rouille::Response::empty_404(),
+    }
+}
+
+fn handle_onion_req(state: &NodeState, faults: &[Fault], body: &[u8]) -> rouille::Response {
+    let mut layer = match v2::decrypt_layer(&state.seckey, body) {
+        Ok(layer) => layer,
+        Err(err) => {
+            eprintln!("[fake node] could not decrypt onion layer: {}", err);
+            return rouille::Response::text("Invalid ciphertext").with_status_code(400);
+        }
+    };
+
+    let corrupt = faults.contains(&Fault::CorruptCiphertext);
+
+    // The target's payload is plaintext, its response gets corrupted instead
+    if corrupt && layer.destination != LayerDestination::Target {
+        faults::corrupt(&mut layer.payload);
+    }
+
+    match &layer.destination {
+        LayerDestination::Node { pubkey_ed25519, .. } => {
+            let refused = faults
+                .iter()
+                .any(|f| f == &Fault::RefuseRelayTo(pubkey_ed25519.clone()));
+
+            if refused {
+                return rouille::Response::text(format!(
+                    "Next node is unreachable: {}",
+                    pubkey_ed25519
+                ))
+                .with_status_code(502);
+            }
+
+            let next = state
+                .directory
+                .read()
+                .iter()
+                .find(|n| &n.pubkey_ed25519 == pubkey_ed25519)
+                .cloned();
+
+            let next = match next {
+                Some(next) => next,
+                None => {
+                    return rouille::Response::text(format!(
+                        "Next node not found: {}",
+                        pubkey_ed25519
+                    ))
+                    .with_status_code(502);
+                }
+            };
+
+            let payload = layer.forward_payload().expect("not a target");
+
+            match relay(&next.storage_url("onion_req/v2"), payload) {
+                Ok((status, body)) => relayed_response(status, body),
+                Err(_) => {
+                    rouille::Response::text(format!("Next node is unreachable: {}", pubkey_ed25519))
+                        .with_status_code(502)
+                }
+            }
+        }
+        LayerDestination::Server {
+            host,
+            target,
+            port,
+            protocol,
+            ..
+        } => {
+            let url = format!(
+                "{}://{}:{}{}",
+                protocol.as_deref().unwrap_or("https"),
+                host,
+                port.unwrap_or(443),
+                target
+            );
+
+            let payload = layer.forward_payload().expect("not a target");
+
+            match relay(&url, payload) {
+                Ok((status, body)) => relayed_response(status, body),
+                Err(_) => rouille::Response::text(format!("Could not contact server: {}", host))
+                    .with_status_code(502),
+            }
+        }
+        // A v4 request is a bencoded list, v2 requests are json
+        LayerDestination::Target if layer.payload.first() == Some(&b'l') => {
+            let (status, body) = match v4::parse_bencoded_pair(&layer.payload) {
+                Ok((_meta, body)) => process_rpc(state, &body),
+                Err(err) => (400, err),
+            };
+
+            let meta = json!({
+                "code": status,
+                "headers": {},
+            });
+
+            let response = v4::bencode_pair(meta.to_string().as_bytes(), body.as_bytes());
+
+            let mut ciphertext =
+                ecdh::encrypt_bytes(layer.enc_type, &response, &layer.symmetric_key);
+
+            if corrupt {
+                faults::corrupt(&mut ciphertext);
+            }
+
+            rouille::Response::from_data("application/octet-stream", ciphertext)
+        }
+        LayerDestination::Target => {
+            let (status, body) = process_rpc(state, &layer.payload);
+
+            let response = json!({
+                "status": status,
+                "body": body,
+            });
+
+            let mut ciphertext = v2::encrypt_response(&layer, response.to_string().as_bytes());
+
+            if corrupt {
+                let mut data = base64::decode(&ciphertext).expect("we just encoded it");
+                faults::corrupt(&mut data);
+                ciphertext = base64::encode(&data);
+            }
+
+            rouille::Response::text(ciphertext)
+        }
+    }
+}
+
+/// Pass the next hop's response back unchanged (v4 responses are binary)
+fn relayed_response(status: u16, body: Vec<u8>) -> rouille::Response {
+    rouille::Response::from_data("application/octet-stream", body).with_status_code(status)
+}
+
+/// Forward the payload to the next hop, returning its status and body
+fn relay(url: &str, payload: Vec<u8>) -> Result<(u16, Vec<u8>), String> {
+    // Handlers don't run inside of the async runtime, so it is fine to block here.
+    // Building a client is slow, so all nodes share one.
+    static CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+
+    let client = CLIENT.get_or_init(|| {
+        reqwest::blocking::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .timeout(Duration::from_secs(30))
+            // A pooled connection would outlive the next node going offline
+            .pool_max_idle_per_host(0)
+            .build()
+            .expect("building reqwest client")
+    });
+
+    let res = client
+        .post(url)
+        .body(payload)
+        .send()
+        .map_err(|err| err.to_string())?;
+
+    let status = res.status().as_u16();
+
+    let body = res.bytes().map_err(|err| err.to_string())?;
+
+    Ok((status, body.to_vec()))
+}
+
+/// Pass a stored message on to the other members of our swarm (ignoring
+/// the ones that are offline)
+fn replicate(state: &NodeState, req: &Value) {
+    let mut req = req.clone();
+    req["params"]["replicated"] = json!(true);
+
+    let payload = req.to_string();
+
+    let info = state.info.read().clone();
+
+    let peers: Vec<ServiceNode> = state
+        .directory
+        .read()
+        .iter()
+        .filter(|n| n.swarm_id == info.swarm_id && n.pubkey_ed25519 != info.pubkey_ed25519)
+        .cloned()
+        .collect();
+
+    for peer in peers {
+        let _ = relay(
+            &peer.storage_url("storage_rpc/v1"),
+            payload.clone().into_bytes(),
+        );
+    }
+}
+
+/// Returns http status and response body
+fn process_rpc(state: &NodeState, body: &[u8]) -> (u16, String) {
+    let req: Value = match serde_json::from_slice(body) {
+        Ok(req) => req,
+        Err(_) => return (400, "invalid json".to_owned()),
+    };
+
+    let method = req["method"].as_str().unwrap_or_default();
+    let params = &req["params"];
+
+    if method == "oxend_request" {
+        return oxend_request(state, params);
+    }
+
+    // Newer methods spell it `pubkey`
+    let pk = match params["pubKey"].as_str().or_else(|| params["pubkey"].as_str()) {
+        Some(pk) => pk.to_owned(),
+        None => return (400, "invalid json: no `pubKey` field".to_owned()),
+    };
+
+    let directory = state.directory.read();
+
+    let swarm_ids: Vec<u64> = directory.iter().map(|n| n.swarm_id).collect();
+
+    let swarm_id = match swarm_for_pubkey(&pk, &swarm_ids) {
+        Some(swarm_id) => swarm_id,
+        None => return (400, "invalid pubkey".to_owned()),
+    };
+
+    let listed_swarm = if state.faults.read().contains(&Fault::WrongSwarm) {
+        state.info.read().swarm_id
+    } else {
+        swarm_id
+    };
+
+    let snodes: Vec<Value> = directory
+        .iter()
+        .filter(|n| n.swarm_id == listed_swarm)
+        .map(|n| {
+            json!({
+                "address": format!("{}.snode", n.pubkey_ed25519),
+                "ip": n.public_ip,
+                "port": n.storage_port.to_string(),
+                "pubkey_ed25519": n.pubkey_ed25519,
+                "pubkey_x25519": n.pubkey_x25519,
+            })
+        })
+        .collect();
+
+    std::mem::drop(directory);
+
+    match method {
+        "get_snodes_for_pubkey" => (200, json!({ "snodes": snodes }).to_string()),
+        "store" => {
+            if swarm_id != state.info.read().swarm_id {
+                return (421, json!({ "snodes": snodes }).to_string());
+            }
+
+            let data = params["data"].as_str().unwrap_or_default().to_owned();
+
+            let parse_u64 = |field: &str| {
+                params[field]
+                    .as_str()
+                    .and_then(|x| x.parse::<u64>().ok())
+                    .unwrap_or_default()
+            };
+
+            let timestamp = parse_u64("timestamp");
+            let ttl = parse_u64("ttl");
+
+            let hash = {
+                let mut hasher = Sha512::new();
+                hasher.input(format!("{}{}{}{}", timestamp, ttl, pk, data));
+                hex::encode(hasher.result())
+            };
+
+            let message = StoredMessage {
+                hash,
+                data,
+                timestamp,
+                ttl,
+            };
+
+            {
+                let mut messages = state.messages.lock();
+                let messages = messages.entry(pk).or_default();
+
+                // Replicas of a message we already have are dropped, as storage servers do
+                if messages.iter().any(|m| m.hash == message.hash) {
+                    return (200, json!({ "difficulty": 1 }).to_string());
+                }
+
+                messages.push(message);
+            }
+
+            // Replicate to the rest of the swarm before answering, so the message
+            // is on every member by the time the client hears back
+            if !params["replicated"].as_bool().unwrap_or_default() {
+                replicate(state, &req);
+            }
+
+            (200, json!({ "difficulty": 1 }).to_string())
+        }
+        "retrieve" => {
+            if swarm_id != state.info.read().swarm_id {
+                return (421, json!({ "snodes": snodes }).to_string());
+            }
+
+            // Unsigned requests are still served, as they were before signatures
+            if !params["signature"].is_null() {
+                let message = format!("retrieve{}", params["timestamp"]);
+
+                if let Err(err) = verify_request(&pk, params, message.as_bytes()) {
+                    return (401, err);
+                }
+            }
+
+            let last_hash = params["lastHash"].as_str().unwrap_or_default();
+
+            let messages = state.messages.lock().get(&pk).cloned().unwrap_or_default();
+
+            // Messages after `last_hash`, or all of them if we don't know it
+            let start = messages
+                .iter()
+                .position(|m| m.hash == last_hash)
+                .map_or(0, |idx| idx + 1);
+
+            let messages: Vec<Value> = messages[start..]
+                .iter()
+                .map(|m| {
+                    json!({
+                        "hash": m.hash,
+                        "expiration": m.timestamp + m.ttl,
+                        "data": m.data,
+                    })
+                })
+                .collect();
+
+            (200, json!({ "messages": messages }).to_string())
+        }
+        "delete" | "expire" => {
+            if swarm_id != state.info.read().swarm_id {
+                return (421, json!({ "snodes": snodes }).to_string());
+            }
+
+            let hashes: Vec<String> = match serde_json::from_value(params["messages"].clone()) {
+                Ok(hashes) => hashes,
+                Err(_) => return (400, "invalid json: no `messages` field".to_owned()),
+            };
+
+            // Only set for `expire`
+            let expiry = match (method, params["expiry"].as_u64()) {
+                ("delete", _) => None,
+                (_, Some(expiry)) => Some(expiry),
+                (_, None) => return (400, "invalid json: no `expiry` field".to_owned()),
+            };
+
+            let message = match expiry {
+                None => format!("delete{}", hashes.concat()),
+                Some(expiry) => format!("expire{}{}", expiry, hashes.concat()),
+            };
+
+            if let Err(err) = verify_request(&pk, params, message.as_bytes()) {
+                return (401, err);
+            }
+
+            let mut messages = state.messages.lock();
+            let messages = messages.entry(pk).or_default();
+
+            let found: Vec<String> = messages
+                .iter()
+                .map(|m| m.hash.clone())
+                .filter(|hash| hashes.contains(hash))
+                .collect();
+
+            match expiry {
+                None => {
+                    messages.retain(|m| !found.contains(&m.hash));
+
+                    (200, json!({ "deleted": found }).to_string())
+                }
+                Some(expiry) => {
+                    for m in messages.iter_mut().filter(|m| found.contains(&m.hash)) {
+                        m.ttl = expiry.saturating_sub(m.timestamp);
+                    }
+
+                    (200, json!({ "updated": found, "expiry": expiry }).to_string())
+                }
+            }
+        }
+        _ => (400, format!("invalid method: {}", method)),
+    }
+}
+
+/// Only `get_service_nodes` is proxied, answered from the directory
+fn oxend_request(state: &NodeState, params: &Value) -> (u16, String) {
+    if params["endpoint"] != "get_service_nodes" {
+        return (
+            400,
+            format!("unsupported oxend endpoint: {}", params["endpoint"]),
+        );
+    }
+
+    let mut nodes = state.directory.read().clone();
+
+    if state.faults.read().contains(&Fault::LieAboutNodes) {
+        let mut made_up = state.info.read().clone();
+
+        made_up.pubkey_ed25519 = hex::encode(rand::random::<[u8; 32]>());
+        made_up.pubkey_x25519 = hex::encode(rand::random::<[u8; 32]>());
+
+        nodes.pop();
+        nodes.push(made_up);
+    }
+
+    let res = json!({
+        "result": {
+            "service_node_states": nodes,
+        }
+    });
+
+    (200, res.to_string())
+}
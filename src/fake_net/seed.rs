@@ -0,0 +1,150 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use parking_lot::RwLock;
+use rand::prelude::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::loki::ServiceNode;
+
+use super::{read_body, LocalServer};
+
+/// Same as the `result` part of oxend's `get_n_service_nodes` response,
+/// so a real response can be saved and used as a fixture directly
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SeedFixture {
+    pub service_node_states: Vec<ServiceNode>,
+}
+
+impl SeedFixture {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+
+        serde_json::from_str(&data).map_err(|err| format!("Invalid fixture: {}", err))
+    }
+
+    #[cfg(test)]
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let data = serde_json::to_string_pretty(self).expect("Could not serialize fixture");
+
+        std::fs::write(path, data)
+            .map_err(|err| format!("Could not write {}: {}", path.display(), err))
+    }
+}
+
+struct SeedState {
+    fixture: RwLock<SeedFixture>,
+    /// Respond with an error when not set, to simulate the seed being down
+    available: AtomicBool,
+}
+
+/// Stand-in for oxend's json rpc, only knows `get_n_service_nodes`
+pub struct FakeSeed {
+    #[cfg(test)]
+    state: Arc<SeedState>,
+    server: LocalServer,
+}
+
+impl FakeSeed {
+    /// Use port 0 for a random port, or 22129 to serve `loki::LOCAL_NET`
+    pub fn start(port: u16, fixture: SeedFixture) -> Result<Self, String> {
+        let state = Arc::new(SeedState {
+            fixture: RwLock::new(fixture),
+            available: AtomicBool::new(true),
+        });
+
+        let state_clone = state.clone();
+
+        let server = LocalServer::start(&format!("127.0.0.1:{}", port), move |req| {
+            handle_request(&state_clone, req)
+        })?;
+
+        Ok(FakeSeed {
+            #[cfg(test)]
+            state,
+            server,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}/json_rpc", self.server.port)
+    }
+
+    /// Network that uses this seed
+    #[cfg(test)]
+    pub fn network(&self) -> crate::loki::Network {
+        use std::borrow::Cow;
+
+        crate::loki::Network {
+            name: Cow::Borrowed("fake"),
+            seed_urls: Cow::Owned(vec![Cow::Owned(self.url())]),
+            ..crate::loki::LOCAL_NET
+        }
+    }
+
+    /// Replace the node list, e.g. to simulate churn between refreshes
+    #[cfg(test)]
+    pub fn set_nodes(&self, nodes: Vec<ServiceNode>) {
+        self.state.fixture.write().service_node_states = nodes;
+    }
+
+    #[cfg(test)]
+    pub fn set_available(&self, available: bool) {
+        self.state.available.store(available, Ordering::Relaxed);
+    }
+}
+
+fn handle_request(state: &SeedState, req: &rouille::Request) -> rouille::Response {
+    if req.method() != "POST" || req.url() != "/json_rpc" {
+        return rouille::Response::empty_404();
+    }
+
+    if !state.available.load(Ordering::Relaxed) {
+        return rouille::Response::text("Seed is unavailable").with_status_code(503);
+    }
+
+    let req: Value = match read_body(req).and_then(|body| serde_json::from_slice(&body).ok()) {
+        Some(req) => req,
+        None => return rouille::Response::text("invalid json").with_status_code(400),
+    };
+
+    let id = req["id"].clone();
+
+    if req["method"] != "get_n_service_nodes" {
+        return rouille::Response::json(&json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": -32601, "message": "Method not found"},
+        }));
+    }
+
+    let fixture = state.fixture.read();
+
+    // Like oxend, a non-zero limit selects a random subset
+    let limit = req["params"]["limit"].as_u64().unwrap_or(0) as usize;
+
+    let nodes: Vec<&ServiceNode> = if limit == 0 {
+        fixture.service_node_states.iter().collect()
+    } else {
+        fixture
+            .service_node_states
+            .choose_multiple(&mut rand::thread_rng(), limit)
+            .collect()
+    };
+
+    rouille::Response::json(&json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": {
+            "service_node_states": nodes,
+            "status": "OK",
+        },
+    }))
+}
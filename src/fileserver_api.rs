@@ -1,6 +1,6 @@
 use crate::{
     http_clients::OnionClient,
-    loki::{LokiServer, ServiceNode},
+    loki::{LokiServer, LokiServerV2, ServiceNode},
     onions::NextHop,
 };
 
@@ -12,6 +12,19 @@ pub struct FileServer {
     pub pubkey: &'static str,
 }
 
+impl FileServer {
+    /// The last hop of onion requests to the server
+    pub fn next_hop(&self) -> NextHop {
+        NextHop::ServerV2(LokiServerV2 {
+            host: self.host.to_owned(),
+            port: 80,
+            protocol: "http".to_owned(),
+            target: "/loki/v3/lsrpc".to_owned(),
+            pubkey_x25519: self.pubkey.to_owned(),
+        })
+    }
+}
+
 pub const CHAT_GETSESSION_ORG: FileServer = FileServer {
     host: "chat.getsession.org",
     pubkey: "be12df7bff19f0ab4ed5d14ae5d8d75d91120781929958a553035e51a48a902d",
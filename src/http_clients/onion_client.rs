@@ -1,10 +1,9 @@
-use loki::LokiServerV2;
-
 use crate::{
     fileserver_api::FileServer,
     loki::{self, LokiServer, ServiceNode},
     node_pool::NodePool,
     onions::NextHop,
+    onions_core::OnionVersion,
 };
 
 use super::Request;
@@ -30,15 +29,23 @@ impl OnionClient {
 
         let payload = req.body.as_bytes();
 
-        let path = self.node_pool.get_random_path();
+        let path = self.node_pool.get_path_for_target(&target)?;
 
-        let res = crate::onions::send_onion_req(path, target, payload, 0).await;
+        let res = crate::onions::send_onion_req(
+            path,
+            target,
+            payload,
+            0,
+            OnionVersion::V2,
+            Default::default(),
+        )
+        .await;
 
         match res {
             Ok(res) => Ok(res),
             Err(err) => {
-                eprintln!("Could not send: {}", err.message);
-                Err(err.message)
+                eprintln!("Could not send: {}", err);
+                Err(err.to_string())
             }
         }
     }
@@ -48,23 +55,22 @@ impl OnionClient {
         server: &FileServer,
         payload: serde_json::Value,
     ) -> Result<String, String> {
-        let host = server.host;
-        let server_key = server.pubkey;
-
-        let target = NextHop::ServerV2(LokiServerV2 {
-            host: host.to_owned(),
-            port: 80,
-            protocol: "http".to_owned(),
-            target: "/loki/v3/lsrpc".to_owned(),
-            pubkey_x25519: server_key.to_owned(),
-        });
+        let target = server.next_hop();
 
         let payload_str = payload.to_string();
         let payload = payload_str.as_bytes();
 
-        let path = self.node_pool.get_random_path();
+        let path = self.node_pool.get_path_for_target(&target)?;
 
-        let res = crate::onions::send_onion_req(path, target, payload, 0).await;
+        let res = crate::onions::send_onion_req(
+            path,
+            target,
+            payload,
+            0,
+            OnionVersion::V2,
+            Default::default(),
+        )
+        .await;
 
         match res {
             Ok(res) => {
@@ -74,8 +80,8 @@ impl OnionClient {
                 Ok(res)
             }
             Err(err) => {
-                eprintln!("Could not send: {}", err.message);
-                Err(err.message)
+                eprintln!("Could not send: {}", err);
+                Err(err.to_string())
             }
         }
     }
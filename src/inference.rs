@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+
+use rand::{prelude::StdRng, Rng, SeedableRng};
+use rand_distr::{Beta, Distribution};
+
+/// Posterior samples to draw, after `BURN_IN` discarded ones
+const SAMPLES: usize = 1000;
+const BURN_IN: usize = 200;
+
+const EM_ITERATIONS: usize = 500;
+
+/// One onion request: the nodes it went through (including the target) and the outcome
+#[derive(Debug, Clone)]
+pub struct Observation {
+    /// Ed25519 keys
+    pub nodes: Vec<String>,
+    pub success: bool,
+}
+
+/// How likely a node is to break requests going through it
+#[derive(Debug, Clone)]
+pub struct NodeEstimate {
+    pub pubkey: String,
+    /// Number of requests the node took part in
+    pub requests: u32,
+    /// Maximum likelihood estimate
+    pub failure_prob: f64,
+    /// 95% credible interval
+    pub interval: (f64, f64),
+}
+
+/// Estimate per-node failure probabilities assuming that every node breaks a request
+/// independently with its own probability, so that a request only works when none
+/// of its nodes broke it. Unlike counting failed paths a node is on, this doesn't
+/// blame the nodes that happened to share paths with a bad one.
+///
+/// Estimates are sorted by the lower end of their interval, so the nodes that are
+/// bad with the most certainty come first.
+pub fn estimate(observations: &[Observation]) -> Vec<NodeEstimate> {
+    let (keys, paths) = index_nodes(observations);
+
+    let mut requests = vec![0u32; keys.len()];
+
+    for (path, _) in &paths {
+        for &node in path {
+            requests[node] += 1;
+        }
+    }
+
+    let mle = maximum_likelihood(&paths, &requests);
+
+    let mut samples = posterior_samples(&paths, &requests);
+
+    let mut estimates: Vec<NodeEstimate> = keys
+        .into_iter()
+        .enumerate()
+        .map(|(node, pubkey)| {
+            let samples = &mut samples[node];
+
+            samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+            let quantile = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+
+            NodeEstimate {
+                pubkey,
+                requests: requests[node],
+                failure_prob: mle[node],
+                interval: (quantile(0.025), quantile(0.975)),
+            }
+        })
+        .collect();
+
+    estimates.sort_by(|a, b| b.interval.0.partial_cmp(&a.interval.0).unwrap());
+
+    estimates
+}
+
+pub fn print_estimates(estimates: &[NodeEstimate], n: usize) {
+    println!(
+        "{:<64} {:>8} {:>9} {:>17}",
+        "node", "requests", "p(fail)", "95% interval"
+    );
+
+    for est in estimates.iter().take(n) {
+        println!(
+            "{:<64} {:>8} {:>9.3} {:>8.3}..{:<8.3}",
+            est.pubkey, est.requests, est.failure_prob, est.interval.0, est.interval.1
+        );
+    }
+}
+
+/// Replace keys with indices, dropping repeated nodes within a path
+fn index_nodes(observations: &[Observation]) -> (Vec<String>, Vec<(Vec<usize>, bool)>) {
+    let mut keys = vec![];
+    let mut indices: HashMap<&str, usize> = HashMap::new();
+
+    let paths = observations
+        .iter()
+        .map(|obs| {
+            let mut path: Vec<usize> = obs
+                .nodes
+                .iter()
+                .map(|key| {
+                    *indices.entry(key).or_insert_with(|| {
+                        keys.push(key.clone());
+                        keys.len() - 1
+                    })
+                })
+                .collect();
+
+            path.sort_unstable();
+            path.dedup();
+
+            (path, obs.success)
+        })
+        .collect();
+
+    (keys, paths)
+}
+
+/// Expectation-maximization, with which node(s) broke a failed request as the hidden variable
+fn maximum_likelihood(paths: &[(Vec<usize>, bool)], requests: &[u32]) -> Vec<f64> {
+    let mut q = vec![0.1; requests.len()];
+
+    for _ in 0..EM_ITERATIONS {
+        let mut expected_failures = vec![0.0; q.len()];
+
+        for (path, success) in paths {
+            if *success {
+                continue;
+            }
+
+            let p_fail = 1.0 - path.iter().map(|&n| 1.0 - q[n]).product::<f64>();
+
+            for &node in path {
+                expected_failures[node] += q[node] / p_fail;
+            }
+        }
+
+        let next: Vec<f64> = expected_failures
+            .iter()
+            .zip(requests)
+            .map(|(failures, requests)| (failures / *requests as f64).min(1.0))
+            .collect();
+
+        let change = next
+            .iter()
+            .zip(&q)
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f64::max);
+
+        q = next;
+
+        if change < 1e-9 {
+            break;
+        }
+    }
+
+    q
+}
+
+/// Gibbs sampling with a uniform prior: alternately pick which nodes broke each
+/// failed request, and draw failure probabilities given those picks
+fn posterior_samples(paths: &[(Vec<usize>, bool)], requests: &[u32]) -> Vec<Vec<f64>> {
+    let mut rng = StdRng::seed_from_u64(0);
+
+    let mut q = vec![0.1; requests.len()];
+    let mut samples = vec![Vec::with_capacity(SAMPLES); q.len()];
+
+    for iteration in 0..BURN_IN + SAMPLES {
+        let mut failures = vec![0u32; q.len()];
+
+        for (path, success) in paths {
+            if !*success {
+                sample_culprits(path, &q, &mut rng, &mut failures);
+            }
+        }
+
+        for node in 0..q.len() {
+            let beta = Beta::new(
+                1.0 + failures[node] as f64,
+                1.0 + (requests[node] - failures[node]) as f64,
+            )
+            .expect("Beta parameters are positive");
+
+            q[node] = beta.sample(&mut rng);
+
+            if iteration >= BURN_IN {
+                samples[node].push(q[node]);
+            }
+        }
+    }
+
+    samples
+}
+
+/// Draw which nodes broke a failed request, given that at least one of them did
+fn sample_culprits(path: &[usize], q: &[f64], rng: &mut StdRng, failures: &mut [u32]) {
+    for (idx, &node) in path.iter().enumerate() {
+        // Probability that at least one of the remaining nodes broke it
+        let p_rest = 1.0 - path[idx..].iter().map(|&n| 1.0 - q[n]).product::<f64>();
+
+        if rng.gen::<f64>() * p_rest < q[node] {
+            failures[node] += 1;
+
+            // Someone is to blame already, the rest are independent
+            for &other in &path[idx + 1..] {
+                if rng.gen::<f64>() < q[other] {
+                    failures[other] += 1;
+                }
+            }
+
+            return;
+        }
+    }
+}
+
+#[test]
+fn test_finds_bad_nodes() {
+    use rand::seq::SliceRandom;
+
+    let mut rng = StdRng::seed_from_u64(1);
+
+    let n_nodes = 30;
+
+    // Two bad nodes, the rest fail now and then
+    let q: Vec<f64> = (0..n_nodes)
+        .map(|n| if n < 2 { 0.4 } else { 0.01 })
+        .collect();
+
+    let nodes: Vec<usize> = (0..n_nodes).collect();
+
+    let observations: Vec<Observation> = (0..4000)
+        .map(|_| {
+            let path: Vec<usize> = nodes.choose_multiple(&mut rng, 4).cloned().collect();
+
+            Observation {
+                success: path.iter().all(|&n| rng.gen::<f64>() >= q[n]),
+                nodes: path.iter().map(|n| n.to_string()).collect(),
+            }
+        })
+        .collect();
+
+    let estimates = estimate(&observations);
+
+    let mut suspects: Vec<&str> = estimates[..2].iter().map(|e| e.pubkey.as_str()).collect();
+    suspects.sort_unstable();
+
+    assert_eq!(suspects, ["0", "1"]);
+
+    let truth = |est: &NodeEstimate| q[est.pubkey.parse::<usize>().unwrap()];
+
+    let covered = |est: &NodeEstimate| est.interval.0 <= truth(est) && truth(est) <= est.interval.1;
+
+    assert!(estimates
+        .iter()
+        .all(|e| (e.failure_prob - truth(e)).abs() < 0.1));
+
+    // A 95% interval can miss now and then
+    assert!(estimates.iter().filter(|e| covered(e)).count() >= 26);
+
+    // Good nodes are told apart from the bad ones with confidence
+    assert!(estimates[2..]
+        .iter()
+        .all(|e| e.interval.1 < estimates[1].interval.0));
+}
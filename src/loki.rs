@@ -1,10 +1,16 @@
-use std::fmt::{self, Debug};
+use std::{
+    borrow::Cow,
+    fmt::{self, Debug},
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
 
 use rand::{prelude::StdRng, RngCore};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ServiceNode {
     #[serde(alias = "ip")]
     pub public_ip: String,
@@ -16,6 +22,9 @@ pub struct ServiceNode {
     pub pubkey_x25519: String,
     pub pubkey_ed25519: String,
     pub swarm_id: u64,
+    /// Only set for local (fake) nodes that don't serve https
+    #[serde(default)]
+    pub plain_http: bool,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -41,6 +50,18 @@ impl fmt::Display for ServiceNode {
     }
 }
 
+impl ServiceNode {
+    /// Url of a storage server endpoint on this node, e.g. `storage_rpc/v1`
+    pub fn storage_url(&self, endpoint: &str) -> String {
+        let scheme = if self.plain_http { "http" } else { "https" };
+
+        format!(
+            "{}://{}:{}/{}",
+            scheme, self.public_ip, self.storage_port, endpoint
+        )
+    }
+}
+
 impl fmt::Display for LokiServer {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // port is most useful when testing locally, might change this for mainnet/testnet
@@ -59,33 +80,177 @@ impl fmt::Display for LokiServerV2 {
     }
 }
 
-#[derive(Clone)]
+/// Everything that differs between networks. Besides the built-in ones below,
+/// networks can be loaded from a json file, e.g.
+/// `{"name": "devnet", "seed_urls": ["http://10.0.0.1:38157/json_rpc"]}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Network {
-    pub seed_url: &'static str,
-    pub is_testnet: bool,
+    pub name: Cow<'static, str>,
+    /// Tried in order until one of them responds
+    pub seed_urls: Cow<'static, [Cow<'static, str>]>,
+    /// Prepended to a hex pubkey to make a Session ID
+    #[serde(default)]
+    pub session_id_prefix: Cow<'static, str>,
+    /// Used for nodes that report port 0 (e.g. those that haven't sent a proof yet)
+    #[serde(default = "default_storage_port")]
+    pub storage_port: u16,
+    #[serde(default = "default_storage_lmq_port")]
+    pub storage_lmq_port: u16,
+    /// Ed25519 keys of nodes that are known to be bad, these never make it into the pool
+    #[serde(default)]
+    pub blocklist: Cow<'static, [Cow<'static, str>]>,
+    /// Where to keep the last known node pool, for when no seed can be reached
+    #[serde(default)]
+    pub snapshot_file: Option<Cow<'static, str>>,
+}
+
+fn default_storage_port() -> u16 {
+    22021
+}
+
+fn default_storage_lmq_port() -> u16 {
+    22020
 }
 
 pub const LOCAL_NET: Network = Network {
-    seed_url: "http://localhost:22129/json_rpc",
-    is_testnet: false,
+    name: Cow::Borrowed("local"),
+    seed_urls: Cow::Borrowed(&[Cow::Borrowed("http://localhost:22129/json_rpc")]),
+    session_id_prefix: Cow::Borrowed("05"),
+    storage_port: 22021,
+    storage_lmq_port: 22020,
+    blocklist: Cow::Borrowed(&[]),
+    snapshot_file: None,
 };
 
 pub const TESTNET: Network = Network {
-    seed_url: "http://public.loki.foundation:38157/json_rpc",
-    is_testnet: true,
+    name: Cow::Borrowed("testnet"),
+    seed_urls: Cow::Borrowed(&[Cow::Borrowed(
+        "http://public.loki.foundation:38157/json_rpc",
+    )]),
+    session_id_prefix: Cow::Borrowed(""),
+    storage_port: 22021,
+    storage_lmq_port: 22020,
+    blocklist: Cow::Borrowed(&[Cow::Borrowed(
+        "decaf01cea9acab5d457a7896d1104752b413f7de864322368820b36ea3abfff",
+    )]),
+    snapshot_file: Some(Cow::Borrowed("node_pool_testnet.json")),
 };
 
 pub const MAINNET: Network = Network {
-    seed_url: "http://public.loki.foundation:22023/json_rpc",
-    is_testnet: false,
+    name: Cow::Borrowed("mainnet"),
+    seed_urls: Cow::Borrowed(&[Cow::Borrowed(
+        "http://public.loki.foundation:22023/json_rpc",
+    )]),
+    session_id_prefix: Cow::Borrowed("05"),
+    storage_port: 22021,
+    storage_lmq_port: 22020,
+    blocklist: Cow::Borrowed(&[]),
+    snapshot_file: Some(Cow::Borrowed("node_pool_mainnet.json")),
 };
 
+impl Network {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+
+        let network: Network = serde_json::from_str(&data)
+            .map_err(|err| format!("Invalid network file {}: {}", path.display(), err))?;
+
+        if network.seed_urls.is_empty() {
+            return Err(format!("No seed urls in {}", path.display()));
+        }
+
+        Ok(network)
+    }
+
+    pub fn is_blocked(&self, node: &ServiceNode) -> bool {
+        self.blocklist.iter().any(|key| key == &node.pubkey_ed25519)
+    }
+
+    /// Drop blocklisted nodes and fill in missing ports
+    pub fn sanitize_nodes(&self, mut nodes: Vec<ServiceNode>) -> Vec<ServiceNode> {
+        let prev = nodes.len();
+
+        nodes.retain(|n| !self.is_blocked(n));
+
+        if prev > nodes.len() {
+            println!("Removed blocklisted nodes: {}", prev - nodes.len());
+        }
+
+        for node in &mut nodes {
+            if node.storage_port == 0 {
+                node.storage_port = self.storage_port;
+            }
+
+            if node.storage_lmq_port == 0 {
+                node.storage_lmq_port = self.storage_lmq_port;
+            }
+        }
+
+        nodes
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    /// One of `mainnet`, `testnet`, `local`, or a path to a network file
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(MAINNET),
+            "testnet" => Ok(TESTNET),
+            "local" => Ok(LOCAL_NET),
+            path => Network::load(Path::new(path)),
+        }
+    }
+}
+
+/// Rounds through all of the network's seeds before giving up
+const SEED_ATTEMPTS: u32 = 3;
+
+/// Wait before the second round, doubled for every round after that
+const SEED_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Ask the network's seeds in order, the first one to respond wins.
+/// Blocklisted nodes are left out.
 pub async fn get_n_service_nodes(
     limit: u32,
     network: &Network,
-) -> Result<Vec<ServiceNode>, &'static str> {
+) -> Result<Vec<ServiceNode>, String> {
     let client = reqwest::Client::new();
 
+    let mut errors = vec![];
+
+    for attempt in 0..SEED_ATTEMPTS {
+        if attempt > 0 {
+            tokio::time::sleep(SEED_RETRY_DELAY * 2u32.pow(attempt - 1)).await;
+        }
+
+        errors.clear();
+
+        for seed_url in network.seed_urls.iter() {
+            match get_n_service_nodes_from_seed(&client, seed_url, limit).await {
+                Ok(nodes) => return Ok(network.sanitize_nodes(nodes)),
+                Err(err) => {
+                    eprintln!("Seed {} failed: {}", seed_url, err);
+                    errors.push(format!("{}: {}", seed_url, err));
+                }
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        return Err("No seed urls".to_owned());
+    }
+
+    Err(format!("All seeds failed ({})", errors.join(", ")))
+}
+
+async fn get_n_service_nodes_from_seed(
+    client: &reqwest::Client,
+    seed_url: &str,
+    limit: u32,
+) -> Result<Vec<ServiceNode>, String> {
     let params = json!({
         "jsonrpc": "2.0",
         "id": "0",
@@ -107,27 +272,35 @@ pub async fn get_n_service_nodes(
     });
 
     let res = client
-        .post(network.seed_url)
+        .post(seed_url)
         .json(&params)
         .send()
         .await
-        .map_err(|_| "Failed to send get_n_service_nodes")?;
+        .map_err(|err| format!("Failed to send get_n_service_nodes: {}", err))?;
 
-    let res_text = res.text().await.map_err(|_| "No text in response")?;
+    if !res.status().is_success() {
+        return Err(format!("Seed responded with {}", res.status()));
+    }
+
+    let res_text = res
+        .text()
+        .await
+        .map_err(|_| "No text in response".to_owned())?;
 
     // println!("SN list Response size: {}", res_text.len());
 
-    let v: Value = serde_json::from_str(&res_text).map_err(|_| "Invalid json")?;
+    let v: Value = serde_json::from_str(&res_text).map_err(|_| "Invalid json".to_owned())?;
 
     let array = &v["result"]["service_node_states"];
 
-    serde_json::from_value(array.clone()).map_err(|_| "Unexpected json structure")
+    serde_json::from_value(array.clone()).map_err(|_| "Unexpected json structure".to_owned())
 }
 
 #[derive(Clone)]
 pub struct PubKey {
     data: [u64; 4],
-    is_testnet: bool,
+    /// Session ID prefix of the network the key is for
+    prefix: Cow<'static, str>,
 }
 
 impl Debug for PubKey {
@@ -137,7 +310,7 @@ impl Debug for PubKey {
 }
 
 impl PubKey {
-    pub fn new(data: &str, is_testnet: bool) -> Option<PubKey> {
+    pub fn new(data: &str, network: &Network) -> Option<PubKey> {
         if data.len() != 64 {
             return None;
         }
@@ -149,7 +322,7 @@ impl PubKey {
 
         Some(PubKey {
             data: [pk0, pk1, pk2, pk3],
-            is_testnet,
+            prefix: network.session_id_prefix.clone(),
         })
     }
 
@@ -163,21 +336,24 @@ impl PubKey {
 
         PubKey {
             data: pk,
-            is_testnet: network.is_testnet,
+            prefix: network.session_id_prefix.clone(),
         }
     }
 
+    /// A random key that storage servers assign to the swarm `swarm_id`, as keys
+    /// go to the swarm closest to the xor of their u64 parts
+    pub fn gen_in_swarm(rng: &mut dyn RngCore, swarm_id: u64, network: &Network) -> PubKey {
+        let mut pk = PubKey::gen_random(rng, network);
+
+        pk.data[3] = swarm_id ^ pk.data[0] ^ pk.data[1] ^ pk.data[2];
+
+        pk
+    }
+
     pub fn to_string(&self) -> String {
-        if self.is_testnet {
-            format!(
-                "{:016x}{:016x}{:016x}{:016x}",
-                self.data[0], self.data[1], self.data[2], self.data[3]
-            )
-        } else {
-            format!(
-                "05{:016x}{:016x}{:016x}{:016x}",
-                self.data[0], self.data[1], self.data[2], self.data[3]
-            )
-        }
+        format!(
+            "{}{:016x}{:016x}{:016x}{:016x}",
+            self.prefix, self.data[0], self.data[1], self.data[2], self.data[3]
+        )
     }
 }
@@ -1,22 +1,29 @@
 use fileserver_api::FileServer;
-use rand::{prelude::StdRng, SeedableRng};
 
 use http_clients::{HttpClient, OnionClient, Request};
 use session_client::SessionClient;
+use session_identity::SessionIdentity;
 
+mod connectivity;
 mod ecdh;
+mod fake_net;
 mod fileserver_api;
+mod inference;
 mod loki;
 mod node_pool;
 mod onions;
 mod onions_core;
+mod path_manager;
+mod pool_snapshot;
 mod proof_of_work;
 mod session_client;
+mod session_identity;
 mod session_server_client;
 mod sn_api;
 mod stats;
 mod swarm_mapping;
 mod tests;
+mod triage;
 
 mod http_clients;
 
@@ -28,14 +35,127 @@ use structopt::StructOpt;
 pub struct ServeOptions {
     #[structopt(short = "p", long = "port", default_value = "8000")]
     port: u16,
+    /// Refresh the node list from this many nodes in the pool (keeping the nodes
+    /// they all agree on) instead of from the seed
+    #[structopt(long = "refresh-from-nodes")]
+    refresh_sources: Option<usize>,
+    /// Weighted request kinds, e.g. `get_snodes=5,store:4096=1,retrieve=2,file_get=1,open_group_poll=1`
+    #[structopt(long = "workload", default_value = "get_snodes=1")]
+    workload: server::Workload,
+    /// Onion request formats to pick from at random for every request, e.g.
+    /// `--onion-version v2 --onion-version v4` to compare the two
+    #[structopt(long = "onion-version", default_value = "v2")]
+    onion_versions: Vec<onions_core::OnionVersion>,
+    /// Don't run store -> retrieve delivery probes
+    #[structopt(long = "no-delivery-probes")]
+    no_delivery_probes: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct FakeNetOptions {
+    /// `loki::LOCAL_NET` expects the seed on 22129
+    #[structopt(long = "seed-port", default_value = "22129")]
+    seed_port: u16,
+    #[structopt(long = "swarms", default_value = "5")]
+    swarms: usize,
+    #[structopt(long = "swarm-size", default_value = "5")]
+    swarm_size: usize,
+    /// Serve nodes from this file instead of starting fake nodes
+    #[structopt(long = "fixture", parse(from_os_str))]
+    fixture: Option<std::path::PathBuf>,
+    /// Inject a fault into a random node: drop, delay=<ms>, status=<code>, corrupt, refuse=<ed25519>, lie, wrong_swarm
+    #[structopt(long = "fault")]
+    faults: Vec<fake_net::Fault>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct BasicOptions {
+    /// Onion request format: v2 or v4
+    #[structopt(long = "onion-version", default_value = "v2")]
+    onion_version: onions_core::OnionVersion,
+    /// Cipher for every hop: aes-gcm or xchacha20
+    #[structopt(long = "enc-type", default_value = "aes-gcm")]
+    enc_type: ecdh::EncryptionType,
+    /// Reuse this many paths instead of a random path for every request
+    #[structopt(long = "paths")]
+    paths: Option<usize>,
+    /// Replace a reused path after this many failures in a row
+    #[structopt(long = "max-path-failures", default_value = "3")]
+    max_path_failures: u32,
+    /// Don't put nodes from the same swarm on one path
+    #[structopt(long = "distinct-swarms")]
+    distinct_swarms: bool,
+    /// Don't put nodes with the same operator on one path
+    #[structopt(long = "distinct-operators")]
+    distinct_operators: bool,
+    /// Don't put nodes from the same /24 subnet on one path
+    #[structopt(long = "distinct-subnets")]
+    distinct_subnets: bool,
+    /// Don't route through the target node
+    #[structopt(long = "exclude-target")]
+    exclude_target: bool,
+    /// Refresh the seed's node list from this many nodes in the pool
+    #[structopt(long = "refresh-from-nodes")]
+    refresh_sources: Option<usize>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct InferOptions {
+    /// Database of the testing server
+    #[structopt(long = "db", default_value = "data.db", parse(from_os_str))]
+    db: std::path::PathBuf,
+    /// Only use requests from this many last hours
+    #[structopt(long = "hours", default_value = "24")]
+    hours: u64,
+    /// How many of the most suspect nodes to list
+    #[structopt(long = "top", default_value = "20")]
+    top: usize,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ConnectivityOptions {
+    /// Test the links between this many nodes from the pool (in both directions)
+    #[structopt(long = "nodes", default_value = "10")]
+    nodes: usize,
+    /// Requests over every link
+    #[structopt(long = "attempts", default_value = "2")]
+    attempts: u32,
+    /// Where to keep the matrix
+    #[structopt(long = "out", default_value = "connectivity.json", parse(from_os_str))]
+    out: std::path::PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SwarmsOptions {
+    /// Ask this many nodes from the pool for a key's swarm
+    #[structopt(long = "nodes", default_value = "20")]
+    nodes: usize,
+}
+
+#[derive(Debug, StructOpt)]
+struct Options {
+    /// mainnet, testnet, local, or a path to a network json file
+    #[structopt(long = "network", default_value = "mainnet", global = true)]
+    network: loki::Network,
+    #[structopt(subcommand)]
+    command: Commands,
 }
 
 #[derive(Debug, StructOpt)]
 enum Commands {
     Serve(ServeOptions),
+    FakeNet(FakeNetOptions),
     Fileserver,
-    Basic,
+    Basic(BasicOptions),
     Stats,
+    /// Estimate which nodes break requests from the results in the server's database
+    Infer(InferOptions),
+    /// Measure which nodes can reach each other
+    Connectivity(ConnectivityOptions),
+    /// Store, retrieve and delete a message for a new Session identity
+    Store,
+    /// Check that nodes map keys to the swarms we compute locally
+    Swarms(SwarmsOptions),
 }
 
 async fn basic_test() {
@@ -46,28 +166,82 @@ async fn basic_test() {
 async fn main() {
     env_logger::init();
 
-    let opt = Commands::from_args();
-
-    let network = loki::MAINNET;
+    let Options { network, command } = Options::from_args();
 
-    match opt {
+    match command {
         Commands::Serve(options) => {
             println!("Starting a testing server...");
             server::start(network, options).await;
         }
+        Commands::FakeNet(options) => {
+            println!("Starting a fake network...");
+            fake_net::run(options).await;
+        }
         Commands::Fileserver => {
             println!("Running fileserver tests");
             tests::test_fileserver_requests(&network).await;
         }
-        Commands::Basic => {
+        Commands::Basic(options) => {
             println!("Running basic tests");
             // basic_test().await;
-            tests::test_onion_requests().await;
+            let path_manager = options
+                .paths
+                .map(|n| path_manager::PathManager::new(n, options.max_path_failures));
+
+            let path_constraints = node_pool::PathConstraints {
+                distinct_swarms: options.distinct_swarms,
+                distinct_operators: options.distinct_operators,
+                distinct_subnets: options.distinct_subnets,
+                exclude_target: options.exclude_target,
+            };
+
+            tests::test_onion_requests(
+                &network,
+                options.onion_version,
+                [options.enc_type; 4],
+                path_manager,
+                path_constraints,
+                options.refresh_sources,
+            )
+            .await;
         }
         Commands::Stats => {
             println!("Obtaining stats from the foundation nodes");
             stats::get_foundation_nodes_stats(&network).await;
         }
+        Commands::Infer(options) => {
+            let period = std::time::Duration::from_secs(options.hours * 60 * 60);
+            server::print_bad_nodes(&options.db, period, options.top);
+        }
+        Commands::Store => {
+            test_session_clients(&network).await;
+        }
+        Commands::Connectivity(options) => {
+            let mut node_pool = node_pool::NodePool::init(&network).await;
+
+            let matrix = connectivity::ConnectivityMatrix::measure(
+                &mut node_pool,
+                options.nodes,
+                options.attempts,
+                &network,
+            )
+            .await;
+
+            matrix.print();
+
+            if let Err(err) = matrix.save(&options.out) {
+                eprintln!("{}", err);
+            }
+        }
+        Commands::Swarms(options) => {
+            let mut node_pool = node_pool::NodePool::init(&network).await;
+
+            let mapping = swarm_mapping::SwarmMapping::new(node_pool.get_all_nodes(), &network);
+
+            let nodes = node_pool.get_random_nodes(options.nodes);
+
+            swarm_mapping::print_answers(&mapping.verify(&nodes).await);
+        }
     }
 
     return;
@@ -129,27 +303,84 @@ async fn main() {
 
     // dbg!(&res);
 
-    // test_session_clients().await;
+    // test_session_clients(&network).await;
 
     // test_clearnet_requests().await;
 
     // tests::test_onion_requests().await;
 }
 
-async fn test_session_clients() {
-    // Generate a session identity and send a message to it
+/// Store a message for a new Session identity, read it back, expire and delete it,
+/// reporting how the swarm members took each request
+async fn test_session_clients(network: &loki::Network) {
+    let alice = SessionIdentity::generate(&mut rand::thread_rng(), network);
 
-    // let alice = SessionClient::new_identity();
+    println!("Session ID: {}", alice.session_id());
 
-    let network = loki::LOCAL_NET;
+    let mut client = SessionClient::new(network).await;
 
-    let mut rng = StdRng::seed_from_u64(0);
+    let data = vec![1, 2, 3];
 
-    let pk = loki::PubKey::gen_random(&mut rng, &network);
+    match client.store_message(&alice.session_id(), &data).await {
+        Ok(results) => print_node_results("stored", &results),
+        Err(err) => {
+            eprintln!("Could not store: {}", err);
+            return;
+        }
+    }
 
-    let client = SessionClient::new(&network).await;
+    let message = {
+        use futures::StreamExt;
+        use std::time::Duration;
 
-    let data = vec![1, 2, 3];
+        let messages = client.poll_messages(&alice, Duration::from_secs(1));
+
+        futures::pin_mut!(messages);
+
+        tokio::time::timeout(Duration::from_secs(10), messages.next()).await
+    };
+
+    let message = match message {
+        Ok(Some(message)) if message.data == data => message,
+        _ => {
+            eprintln!("Could not retrieve the message");
+            return;
+        }
+    };
+
+    println!("Retrieved message {}", message.hash);
+
+    let hashes = [message.hash];
+
+    // Both authenticated requests should work, the expiry is cut short before deleting
+    let in_a_minute = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Could not get UNIX time")
+        .as_millis() as u64
+        + 60_000;
+
+    let expiry = message.expiration.min(in_a_minute);
+
+    match client.expire_messages(&alice, &hashes, expiry).await {
+        Ok(results) => print_node_results("expiry updated", &results),
+        Err(err) => eprintln!("Could not expire: {}", err),
+    }
+
+    match client.delete_messages(&alice, &hashes).await {
+        Ok(results) => print_node_results("deleted", &results),
+        Err(err) => eprintln!("Could not delete: {}", err),
+    }
+}
+
+fn print_node_results(action: &str, results: &[session_client::NodeResult]) {
+    for res in results {
+        match &res.result {
+            Ok(()) => println!("{}: {}", res.node, action),
+            Err(err) => println!("{}: {}", res.node, err),
+        }
+    }
+
+    let accepted = results.iter().filter(|res| res.result.is_ok()).count();
 
-    client.store_message(&pk.to_string(), &data).await;
+    println!("Accepted by {}/{} nodes", accepted, results.len());
 }
@@ -1,30 +1,192 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
 
 use rand::{
     prelude::{SliceRandom, StdRng},
     SeedableRng,
 };
 
-use crate::loki::{self, ServiceNode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    loki::{self, ServiceNode},
+    onions::{NextHop, OnionPath},
+    pool_snapshot::{PoolDiff, PoolSnapshot},
+    sn_api,
+};
+
+/// How much the latest outcome contributes to the score
+const SCORE_DECAY: f64 = 0.1;
+
+/// Nodes with the worst score still get picked now and then,
+/// otherwise they could never recover
+const MIN_WEIGHT: f64 = 0.01;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeReputation {
+    pub successes: u32,
+    pub failures: u32,
+    /// Moving average of outcomes (1 for success, 0 for failure),
+    /// so older requests matter less and less
+    pub score: f64,
+}
+
+impl Default for NodeReputation {
+    /// Nodes we know nothing about are assumed to be good
+    fn default() -> Self {
+        NodeReputation {
+            successes: 0,
+            failures: 0,
+            score: 1.0,
+        }
+    }
+}
+
+impl NodeReputation {
+    fn record(&mut self, success: bool) {
+        if success {
+            self.successes += 1;
+        } else {
+            self.failures += 1;
+        }
+
+        let outcome = if success { 1.0 } else { 0.0 };
+
+        self.score = self.score * (1.0 - SCORE_DECAY) + outcome * SCORE_DECAY;
+    }
+}
+
+/// Which nodes are allowed on the same path. Nodes close to each other
+/// (in the same swarm, run by the same operator or in the same /24 network)
+/// might fail together, so these control how diverse paths are.
+#[derive(Debug, Clone, Default)]
+pub struct PathConstraints {
+    pub distinct_swarms: bool,
+    pub distinct_operators: bool,
+    pub distinct_subnets: bool,
+    /// Don't route through the node the request is for
+    pub exclude_target: bool,
+}
+
+impl PathConstraints {
+    /// Whether `node` can be added to a path that already has `path`
+    fn allows(&self, path: &[ServiceNode], node: &ServiceNode) -> bool {
+        path.iter().all(|other| {
+            other.pubkey_ed25519 != node.pubkey_ed25519
+                && !(self.distinct_swarms && other.swarm_id == node.swarm_id)
+                && !(self.distinct_operators && other.operator_address == node.operator_address)
+                && !(self.distinct_subnets
+                    && subnet_24(&other.public_ip) == subnet_24(&node.public_ip))
+        })
+    }
+}
+
+/// First three octets of an ipv4 address
+fn subnet_24(ip: &str) -> Option<&str> {
+    ip.rfind('.').map(|pos| &ip[..pos])
+}
 
 #[derive(Debug)]
 pub struct NodePool {
     node_pool: Vec<ServiceNode>,
     rng: StdRng,
+    /// By ed25519 key, as that is all we know about targets
+    reputation: HashMap<String, NodeReputation>,
+    path_constraints: PathConstraints,
 }
 
 impl NodePool {
-    /// Initialize from network's seed
+    /// Initialize from network's seed. If the network has a snapshot file, the
+    /// pool is also kept there and loaded from it when no seed can be reached.
     pub async fn init(net: &loki::Network) -> Self {
-        let node_pool = loki::get_n_service_nodes(0, net)
-            .await
-            .expect("Could not initialize node pool");
+        let path = match &net.snapshot_file {
+            Some(path) => Path::new(path.as_ref()),
+            None => {
+                return NodePool::try_init(net)
+                    .await
+                    .expect("Could not initialize node pool")
+            }
+        };
+
+        let snapshot = PoolSnapshot::load(path)
+            .map_err(|err| eprintln!("No pool snapshot: {}", err))
+            .ok();
+
+        let node_pool = match (NodePool::try_init(net).await, snapshot) {
+            (Ok(node_pool), None) => node_pool,
+            (Ok(mut node_pool), Some(snapshot)) => {
+                PoolDiff::new(&snapshot.nodes, &node_pool.node_pool).print();
+                node_pool.reputation = snapshot.reputation;
+                node_pool
+            }
+            (Err(err), Some(snapshot)) => {
+                eprintln!(
+                    "Could not get nodes from the seed ({}), using the snapshot from {}",
+                    err, snapshot.saved_at
+                );
+                NodePool::from_snapshot(snapshot)
+            }
+            (Err(err), None) => panic!("Could not initialize node pool: {}", err),
+        };
+
+        if let Err(err) = node_pool.save(path) {
+            eprintln!("{}", err);
+        }
+
+        node_pool
+    }
+
+    /// Same as `init`, but lets the caller handle an unreachable seed
+    pub async fn try_init(net: &loki::Network) -> Result<Self, String> {
+        let node_pool = loki::get_n_service_nodes(0, net).await?;
 
         // println!("Node pool: {:#?}", node_pool);
 
+        Ok(NodePool::from_nodes(node_pool))
+    }
+
+    /// Initialize from a known list of nodes (e.g. local fake nodes)
+    pub fn from_nodes(node_pool: Vec<ServiceNode>) -> Self {
         let rng = StdRng::seed_from_u64(0);
 
-        NodePool { node_pool, rng }
+        NodePool {
+            node_pool,
+            rng,
+            reputation: HashMap::new(),
+            path_constraints: PathConstraints::default(),
+        }
+    }
+
+    pub fn from_snapshot(snapshot: PoolSnapshot) -> Self {
+        NodePool {
+            reputation: snapshot.reputation,
+            ..NodePool::from_nodes(snapshot.nodes)
+        }
+    }
+
+    /// Keep the nodes along with their reputation
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        PoolSnapshot::new(self.node_pool.clone(), self.reputation.clone()).save(path)
+    }
+
+    /// Replace the nodes with those that `n_sources` nodes from the pool agree on
+    /// (reputation is kept)
+    pub async fn refresh_from_nodes(
+        &mut self,
+        net: &loki::Network,
+        n_sources: usize,
+    ) -> Result<(), String> {
+        let sources = self.get_random_nodes(n_sources);
+
+        let nodes = fetch_from_nodes(&sources, net).await?;
+
+        PoolDiff::new(&self.node_pool, &nodes).print();
+
+        self.node_pool = nodes;
+
+        Ok(())
     }
 
     pub fn remove_non_foundation(&mut self) {
@@ -39,21 +201,160 @@ impl NodePool {
         self.node_pool.truncate(len);
     }
 
+    pub fn set_path_constraints(&mut self, constraints: PathConstraints) {
+        self.path_constraints = constraints;
+    }
+
+    /// Nodes with a lower score are less likely to be picked
+    #[cfg(test)]
     pub fn get_random_path(&mut self) -> [ServiceNode; 3] {
-        let mut iter = self.node_pool.choose_multiple(&mut self.rng, 3).cloned();
+        self.build_path(&[], |_| false)
+            .expect("Not enough nodes to satisfy path constraints")
+    }
+
+    /// Same as `get_random_path`, but can keep `target` off the path. Fails if
+    /// there aren't enough nodes to satisfy path constraints.
+    pub fn get_path_for_target(&mut self, target: &NextHop) -> Result<[ServiceNode; 3], String> {
+        let target_key = self.excluded_target(target);
 
-        [
-            iter.next().unwrap(),
-            iter.next().unwrap(),
-            iter.next().unwrap(),
-        ]
+        self.build_path(&[], |n| Some(&n.pubkey_ed25519) == target_key.as_ref())
+            .ok_or_else(|| "Not enough nodes to satisfy path constraints".to_owned())
     }
 
+    /// Ed25519 key of the node that paths to `target` must not go through, if any
+    pub fn excluded_target(&self, target: &NextHop) -> Option<String> {
+        match target {
+            NextHop::Node(n) if self.path_constraints.exclude_target => {
+                Some(n.pubkey_ed25519.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// A path starting with `fixed` (e.g. a guard we want to keep) followed by random
+    /// nodes that satisfy path constraints, never picking nodes for which `skip` is true.
+    /// `None` if there aren't enough suitable nodes.
+    pub fn build_path<F>(&mut self, fixed: &[ServiceNode], skip: F) -> Option<[ServiceNode; 3]>
+    where
+        F: Fn(&ServiceNode) -> bool,
+    {
+        let mut path: Vec<ServiceNode> = fixed.to_vec();
+
+        while path.len() < 3 {
+            let candidates: Vec<usize> = (0..self.node_pool.len())
+                .filter(|idx| {
+                    let node = &self.node_pool[*idx];
+                    !skip(node) && self.path_constraints.allows(&path, node)
+                })
+                .collect();
+
+            let idx = self.choose_weighted(&candidates)?;
+
+            path.push(self.node_pool[idx].clone());
+        }
+
+        let mut iter = path.into_iter();
+
+        Some([iter.next()?, iter.next()?, iter.next()?])
+    }
+
+    /// Distinct nodes, with lower scoring nodes less likely to be picked
     pub fn get_random_nodes(&mut self, n: usize) -> Vec<ServiceNode> {
-        self.node_pool
-            .choose_multiple(&mut self.rng, n)
-            .cloned()
-            .collect()
+        let mut candidates: Vec<usize> = (0..self.node_pool.len()).collect();
+
+        let mut nodes = vec![];
+
+        while nodes.len() < n {
+            let idx = match self.choose_weighted(&candidates) {
+                Some(idx) => idx,
+                None => break,
+            };
+
+            candidates.retain(|c| *c != idx);
+
+            nodes.push(self.node_pool[idx].clone());
+        }
+
+        nodes
+    }
+
+    /// Index (into `node_pool`) of one of the `candidates`, weighted by score
+    fn choose_weighted(&mut self, candidates: &[usize]) -> Option<usize> {
+        let node_pool = &self.node_pool;
+        let reputation = &self.reputation;
+
+        candidates
+            .choose_weighted(&mut self.rng, |idx| {
+                reputation
+                    .get(&node_pool[*idx].pubkey_ed25519)
+                    .map_or(1.0, |r| r.score)
+                    .max(MIN_WEIGHT)
+            })
+            .ok()
+            .copied()
+    }
+
+    /// Every node on the path did its job
+    pub fn report_success(&mut self, path: &OnionPath) {
+        for hop in &[&path.node_1, &path.node_2, &path.node_3, &path.target] {
+            self.record(hop, true);
+        }
+    }
+
+    /// Only `hop` is blamed if known, otherwise every node on the path is
+    pub fn report_failure(&mut self, path: &OnionPath, hop: Option<&NextHop>) {
+        match hop {
+            Some(hop) => self.record(hop, false),
+            None => {
+                for hop in &[&path.node_1, &path.node_2, &path.node_3, &path.target] {
+                    self.record(hop, false);
+                }
+            }
+        }
+    }
+
+    fn record(&mut self, hop: &NextHop, success: bool) {
+        if let NextHop::Node(node) = hop {
+            self.reputation
+                .entry(node.pubkey_ed25519.clone())
+                .or_default()
+                .record(success);
+        }
+    }
+
+    /// Nodes we have seen requests for, worst score first
+    pub fn scoreboard(&self) -> Vec<(&ServiceNode, NodeReputation)> {
+        let mut scoreboard: Vec<_> = self
+            .node_pool
+            .iter()
+            .filter_map(|n| {
+                self.reputation
+                    .get(&n.pubkey_ed25519)
+                    .map(|r| (n, r.clone()))
+            })
+            .collect();
+
+        scoreboard.sort_by(|a, b| a.1.score.partial_cmp(&b.1.score).unwrap());
+
+        scoreboard
+    }
+
+    /// Print the `n` worst nodes
+    pub fn print_scoreboard(&self, n: usize) {
+        println!(
+            "{:<24} {:>8} {:>8} {:>6}",
+            "node", "success", "failure", "score"
+        );
+
+        for (node, rep) in self.scoreboard().into_iter().take(n) {
+            println!(
+                "{:<24} {:>8} {:>8} {:>6.3}",
+                node.to_string(),
+                rep.successes,
+                rep.failures,
+                rep.score
+            );
+        }
     }
 
     pub fn get_all_nodes(&self) -> &Vec<ServiceNode> {
@@ -61,7 +362,7 @@ impl NodePool {
     }
 
     pub fn swarm_count(&self) -> usize {
-        let mut swarms : HashSet<u64> = HashSet::new();
+        let mut swarms: HashSet<u64> = HashSet::new();
 
         for n in &self.node_pool {
             swarms.insert(n.swarm_id);
@@ -70,3 +371,192 @@ impl NodePool {
         swarms.len()
     }
 }
+
+/// Ask each of `sources` for the node list, keeping only the nodes a majority of
+/// the responses agree on, so that a single node can't add made-up nodes, change
+/// keys or leave nodes out
+pub async fn fetch_from_nodes(
+    sources: &[ServiceNode],
+    net: &loki::Network,
+) -> Result<Vec<ServiceNode>, String> {
+    let responses = futures::future::join_all(sources.iter().map(sn_api::get_service_nodes)).await;
+
+    let mut lists = vec![];
+
+    for (source, res) in sources.iter().zip(responses) {
+        match res {
+            Ok(nodes) => lists.push((source, nodes)),
+            Err(err) => eprintln!("Could not get nodes from {}: {}", source, err),
+        }
+    }
+
+    if lists.len() <= sources.len() / 2 {
+        return Err(format!(
+            "Only {} of {} nodes responded",
+            lists.len(),
+            sources.len()
+        ));
+    }
+
+    let key = |n: &ServiceNode| {
+        (
+            n.pubkey_ed25519.clone(),
+            n.pubkey_x25519.clone(),
+            n.public_ip.clone(),
+            n.storage_port,
+            n.swarm_id,
+        )
+    };
+
+    let mut votes: HashMap<_, usize> = HashMap::new();
+
+    for (_, nodes) in &lists {
+        let keys: HashSet<_> = nodes.iter().map(key).collect();
+
+        for k in keys {
+            *votes.entry(k).or_default() += 1;
+        }
+    }
+
+    let agreed: HashSet<_> = votes
+        .into_iter()
+        .filter(|(_, count)| *count > lists.len() / 2)
+        .map(|(k, _)| k)
+        .collect();
+
+    for (source, nodes) in &lists {
+        let disputed = nodes.iter().filter(|n| !agreed.contains(&key(n))).count();
+
+        if disputed > 0 {
+            println!(
+                "{} reported {} nodes the others disagree on",
+                source, disputed
+            );
+        }
+    }
+
+    // Every agreed node once, as the majority described it
+    let mut seen = HashSet::new();
+
+    let nodes = lists
+        .into_iter()
+        .flat_map(|(_, nodes)| nodes)
+        .filter(|n| agreed.contains(&key(n)) && seen.insert(key(n)))
+        .collect();
+
+    Ok(net.sanitize_nodes(nodes))
+}
+
+#[cfg(test)]
+pub(crate) fn test_nodes(n: u8) -> Vec<ServiceNode> {
+    (0..n)
+        .map(|idx| ServiceNode {
+            public_ip: "127.0.0.1".to_owned(),
+            storage_port: 8080 + idx as u16,
+            storage_lmq_port: 0,
+            service_node_pubkey: "".to_owned(),
+            operator_address: "".to_owned(),
+            pubkey_x25519: "".to_owned(),
+            pubkey_ed25519: hex::encode([idx; 32]),
+            swarm_id: 0,
+            plain_http: true,
+        })
+        .collect()
+}
+
+#[test]
+fn test_reputation_biases_selection() {
+    let nodes = test_nodes(10);
+
+    let mut node_pool = NodePool::from_nodes(nodes.clone());
+
+    let path = |target: &ServiceNode| OnionPath {
+        node_1: NextHop::Node(nodes[0].clone()),
+        node_2: NextHop::Node(nodes[1].clone()),
+        node_3: NextHop::Node(nodes[2].clone()),
+        target: NextHop::Node(target.clone()),
+    };
+
+    let bad = NextHop::Node(nodes[1].clone());
+
+    node_pool.report_success(&path(&nodes[3]));
+    node_pool.report_failure(&path(&nodes[4]), None);
+
+    for _ in 0..50 {
+        node_pool.report_failure(&path(&nodes[3]), Some(&bad));
+    }
+
+    let scoreboard = node_pool.scoreboard();
+
+    // Only nodes we have seen, worst first
+    assert_eq!(scoreboard.len(), 5);
+    assert_eq!(scoreboard[0].0.pubkey_ed25519, nodes[1].pubkey_ed25519);
+    // Blamed along with the rest of the path once
+    assert_eq!(scoreboard[0].1.failures, 51);
+    assert_eq!(scoreboard[0].1.successes, 1);
+    assert!(scoreboard[0].1.score < 0.1);
+
+    let first = scoreboard
+        .iter()
+        .find(|(n, _)| n.pubkey_ed25519 == nodes[0].pubkey_ed25519)
+        .unwrap();
+
+    assert_eq!((first.1.successes, first.1.failures), (1, 1));
+
+    let picked = (0..200)
+        .flat_map(|_| node_pool.get_random_nodes(3))
+        .filter(|n| n.pubkey_ed25519 == nodes[1].pubkey_ed25519)
+        .count();
+
+    // ~60 out of 600 if it wasn't penalized
+    assert!(picked < 20, "bad node picked {} times", picked);
+}
+
+#[test]
+fn test_path_constraints() {
+    let mut nodes = test_nodes(12);
+
+    // Two nodes per swarm, operator and subnet, each pair sharing a different one
+    for (idx, node) in nodes.iter_mut().enumerate() {
+        node.swarm_id = idx as u64 / 2;
+        node.operator_address = format!("operator_{}", (idx + 1) / 2 % 6);
+        node.public_ip = format!("10.0.{}.{}", idx % 6, idx);
+    }
+
+    let target = NextHop::Node(nodes[0].clone());
+    let target_key = nodes[0].pubkey_ed25519.clone();
+
+    let mut node_pool = NodePool::from_nodes(nodes);
+
+    node_pool.set_path_constraints(PathConstraints {
+        distinct_swarms: true,
+        distinct_operators: true,
+        distinct_subnets: true,
+        exclude_target: true,
+    });
+
+    for _ in 0..100 {
+        let path = node_pool.get_path_for_target(&target).unwrap();
+
+        for (i, a) in path.iter().enumerate() {
+            assert_ne!(a.pubkey_ed25519, target_key);
+
+            for b in &path[i + 1..] {
+                assert_ne!(a.swarm_id, b.swarm_id);
+                assert_ne!(a.operator_address, b.operator_address);
+                assert_ne!(subnet_24(&a.public_ip), subnet_24(&b.public_ip));
+            }
+        }
+    }
+
+    // Every node is in the same /24, so no path is possible
+    let mut node_pool = NodePool::from_nodes(test_nodes(5));
+
+    node_pool.set_path_constraints(PathConstraints {
+        distinct_subnets: true,
+        ..Default::default()
+    });
+
+    assert!(node_pool.build_path(&[], |_| false).is_none());
+    assert!(node_pool.get_path_for_target(&target).is_err());
+}
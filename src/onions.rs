@@ -1,8 +1,13 @@
-use std::fmt;
+use std::{fmt, time::Duration};
 
 use rand::prelude::*;
 
-use crate::{loki::{LokiServer, LokiServerV2, ServiceNode}, sn_api};
+use crate::{
+    ecdh::EncryptionType,
+    loki::{LokiServer, LokiServerV2, ServiceNode},
+    onions_core::OnionVersion,
+    sn_api,
+};
 
 pub trait HasX25519 {
     fn pubkey_x25519(&self) -> String;
@@ -45,7 +50,7 @@ impl fmt::Display for NextHop {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct OnionPath {
     pub node_1: NextHop,
     pub node_2: NextHop,
@@ -53,16 +58,180 @@ pub struct OnionPath {
     pub target: NextHop,
 }
 
+impl OnionPath {
+    /// The hop that is the node with this ed25519 key
+    pub fn find_node(&self, pubkey_ed25519: &str) -> Option<&NextHop> {
+        [&self.node_1, &self.node_2, &self.node_3, &self.target]
+            .iter()
+            .cloned()
+            .find(|hop| matches!(hop, NextHop::Node(n) if n.pubkey_ed25519 == pubkey_ed25519))
+    }
+}
+
+/// What went wrong with an onion request
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnionErrorKind {
+    /// Could not send the request to the guard node or read its response
+    GuardTransport(String),
+    /// The guard responded with an error status that doesn't name a failing hop
+    /// (it might have come from further down the path)
+    GuardStatus { status: u16, body: String },
+    /// A node on the path doesn't know the node with this ed25519 key
+    NextNodeNotFound(String),
+    /// A node on the path could not reach the node with this ed25519 key
+    NextNodeUnreachable(String),
+    /// The response from the target could not be decrypted
+    Decryption,
+    /// The response was decrypted, but is not in the expected format
+    InvalidResponse(String),
+    /// The payload can't be sent the way the onion version needs (never left this machine)
+    InvalidRequest(String),
+    /// The request made it to the target, which responded with an error status
+    Target { status: u32, body: String },
+}
+
+impl OnionErrorKind {
+    /// Short name of the variant, e.g. for grouping errors in a database
+    pub fn name(&self) -> &'static str {
+        match self {
+            OnionErrorKind::GuardTransport(_) => "guard_transport",
+            OnionErrorKind::GuardStatus { .. } => "guard_status",
+            OnionErrorKind::NextNodeNotFound(_) => "next_node_not_found",
+            OnionErrorKind::NextNodeUnreachable(_) => "next_node_unreachable",
+            OnionErrorKind::Decryption => "decryption",
+            OnionErrorKind::InvalidResponse(_) => "invalid_response",
+            OnionErrorKind::InvalidRequest(_) => "invalid_request",
+            OnionErrorKind::Target { .. } => "target",
+        }
+    }
+
+    /// Responses from the guard with a non-success status. Nodes name the next hop
+    /// when they can't reach it, and relay error responses from further down as is.
+    pub fn from_guard_response(status: u16, body: &str) -> Self {
+        const NOT_FOUND: &str = "Next node not found: ";
+        const UNREACHABLE: &str = "Next node is unreachable: ";
+
+        let named_key = |prefix: &str| {
+            body.find(prefix)
+                .map(|idx| body[idx + prefix.len()..].trim().to_owned())
+        };
+
+        if let Some(key) = named_key(NOT_FOUND) {
+            OnionErrorKind::NextNodeNotFound(key)
+        } else if let Some(key) = named_key(UNREACHABLE) {
+            OnionErrorKind::NextNodeUnreachable(key)
+        } else {
+            OnionErrorKind::GuardStatus {
+                status,
+                body: body.to_owned(),
+            }
+        }
+    }
+}
+
+impl fmt::Display for OnionErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OnionErrorKind::GuardTransport(err) => write!(f, "Could not send request: {}", err),
+            OnionErrorKind::GuardStatus { status, body } => {
+                write!(f, "😵 Onion request failed: [{}] <{}>", status, body)
+            }
+            OnionErrorKind::NextNodeNotFound(key) => write!(f, "Next node not found: {}", key),
+            OnionErrorKind::NextNodeUnreachable(key) => {
+                write!(f, "Next node is unreachable: {}", key)
+            }
+            OnionErrorKind::Decryption => write!(f, "Decryption error"),
+            OnionErrorKind::InvalidResponse(err) => write!(f, "Invalid onion response: {}", err),
+            OnionErrorKind::InvalidRequest(err) => write!(f, "Invalid onion request: {}", err),
+            OnionErrorKind::Target { status, body } => {
+                write!(f, "Target responded with [{}] <{}>", status, body)
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct OnionError {
-    pub message: String,
+    pub kind: OnionErrorKind,
     pub path: OnionPath,
 }
 
+impl OnionError {
+    /// The hop the error can be pinned on, `None` if any node on the path could be at fault
+    pub fn culprit(&self) -> Option<&NextHop> {
+        match &self.kind {
+            OnionErrorKind::GuardTransport(_) => Some(&self.path.node_1),
+            OnionErrorKind::NextNodeNotFound(key) | OnionErrorKind::NextNodeUnreachable(key) => {
+                self.path.find_node(key)
+            }
+            OnionErrorKind::Target { .. } => Some(&self.path.target),
+            OnionErrorKind::GuardStatus { .. }
+            | OnionErrorKind::Decryption
+            | OnionErrorKind::InvalidResponse(_)
+            | OnionErrorKind::InvalidRequest(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for OnionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
 #[derive(serde::Deserialize)]
-struct OnionResponse {
-    body: String,
-    status: u32,
+pub struct OnionResponse {
+    pub body: String,
+    pub status: u32,
+}
+
+/// Where the time of a single onion request went
+#[derive(Debug, Clone, Default)]
+pub struct OnionTiming {
+    /// Encrypting each layer, in the order they are built: target, node 3, node 2, node 1
+    pub layers: [Duration; 4],
+    /// TCP and TLS handshake with the guard
+    pub connect: Duration,
+    /// From sending the request until the guard's response headers arrive, i.e.
+    /// uploading the request plus the time spent travelling through the path
+    pub round_trip: Duration,
+    /// Reading the response body
+    pub download: Duration,
+    /// Decrypting the target's response
+    pub decryption: Duration,
+}
+
+impl OnionTiming {
+    pub const COMPONENTS: [&'static str; 8] = [
+        "encrypt target",
+        "encrypt node 3",
+        "encrypt node 2",
+        "encrypt node 1",
+        "connect",
+        "round trip",
+        "download",
+        "decryption",
+    ];
+
+    /// Durations in the same order as `COMPONENTS`
+    pub fn components(&self) -> [Duration; 8] {
+        let [l0, l1, l2, l3] = self.layers;
+
+        [
+            l0,
+            l1,
+            l2,
+            l3,
+            self.connect,
+            self.round_trip,
+            self.download,
+            self.decryption,
+        ]
+    }
+
+    pub fn total(&self) -> Duration {
+        self.components().iter().sum()
+    }
 }
 
 pub async fn send_onion_req(
@@ -70,7 +239,24 @@ pub async fn send_onion_req(
     target: NextHop,
     payload: &[u8],
     i: u64,
+    version: OnionVersion,
+    enc_types: [EncryptionType; 4],
 ) -> Result<String, OnionError> {
+    send_onion_req_timed(node_path, target, payload, i, version, enc_types)
+        .await
+        .0
+}
+
+/// Same as `send_onion_req`, but also reports where the time went
+/// (for failed requests only the steps that were reached are set)
+pub async fn send_onion_req_timed(
+    node_path: [ServiceNode; 3],
+    target: NextHop,
+    payload: &[u8],
+    i: u64,
+    version: OnionVersion,
+    enc_types: [EncryptionType; 4],
+) -> (Result<String, OnionError>, OnionTiming) {
     let [n1, n2, n3] = node_path;
 
     let guard_pubkey = &n1.service_node_pubkey;
@@ -91,19 +277,36 @@ pub async fn send_onion_req(
         target,
     };
 
-    let client = reqwest::Client::builder()
-        .danger_accept_invalid_certs(true)
-        .timeout(std::time::Duration::from_secs(60))
-        .build()
-        .expect("building reqwest client");
+    let mut timing = OnionTiming::default();
+
+    let res = match version {
+        OnionVersion::V2 => sn_api::onion_request_v2(&path, &payload, enc_types, &mut timing)
+            .await
+            .and_then(|res| {
+                serde_json::from_str(&res)
+                    .map_err(|err| OnionErrorKind::InvalidResponse(err.to_string()))
+            }),
+        OnionVersion::V4 => sn_api::onion_request_v4(&path, &payload, enc_types, &mut timing).await,
+    };
 
-    let res = sn_api::onion_request_v2(&client, &path, &payload).await;
+    let res: OnionResponse = match res {
+        Ok(res) => res,
+        Err(kind) => return (Err(OnionError { kind, path }), timing),
+    };
 
-    let res = res.map_err(|err| OnionError { message: err, path })?;
+    if !(200..300).contains(&res.status) {
+        let err = OnionError {
+            kind: OnionErrorKind::Target {
+                status: res.status,
+                body: res.body,
+            },
+            path,
+        };
 
-    let res: OnionResponse = serde_json::from_str(&res).expect("Not a valid onion response");
+        return (Err(err), timing);
+    }
 
-    Ok(res.body)
+    (Ok(res.body), timing)
 }
 
 // Theories that I want to test:
@@ -1,2 +1,33 @@
+use std::{fmt, str::FromStr};
+
 /// Binary protocol for onions
 pub mod v2;
+/// Same layers as v2, bencoded request and response for the target
+pub mod v4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnionVersion {
+    V2,
+    V4,
+}
+
+impl FromStr for OnionVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v2" => Ok(OnionVersion::V2),
+            "v4" => Ok(OnionVersion::V4),
+            _ => Err(format!("Unknown onion version: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for OnionVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OnionVersion::V2 => write!(f, "v2"),
+            OnionVersion::V4 => write!(f, "v4"),
+        }
+    }
+}
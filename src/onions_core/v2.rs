@@ -1,21 +1,46 @@
-use crate::{ecdh, loki::{LokiServer, LokiServerV2, ServiceNode}, onions::{NextHop, OnionPath}};
-use byteorder::{LittleEndian, WriteBytesExt};
+use crate::{
+    ecdh::{self, EncryptionType},
+    loki::{LokiServer, LokiServerV2, ServiceNode},
+    onions::{NextHop, OnionPath, OnionTiming},
+};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use serde_json::json;
 
-pub async fn onion_request(path: &OnionPath, payload: &[u8]) -> (Vec<u8>, Vec<u8>) {
+/// `enc_types` are the ciphers for node 1, node 2, node 3 and the target,
+/// the response is encrypted with the target's
+pub async fn onion_request(
+    path: &OnionPath,
+    payload: &[u8],
+    enc_types: [EncryptionType; 4],
+    timing: &mut OnionTiming,
+) -> (Vec<u8>, Vec<u8>) {
+    let [enc_1, enc_2, enc_3, enc_target] = enc_types;
+
+    let time_now = std::time::Instant::now();
+
     let ctx_1 = match &path.target {
-        NextHop::Node(node) => encrypt_for_target_node(&node, &payload),
-        NextHop::Server(server) => encrypt_for_target_server(&server, &payload),
-        NextHop::ServerV2(server) => encrypt_for_target_server_v2(&server, &payload),
+        NextHop::Node(node) => encrypt_for_target_node(&node, &payload, enc_target),
+        NextHop::Server(server) => encrypt_for_target_server(&server, &payload, enc_target),
+        NextHop::ServerV2(server) => encrypt_for_target_server_v2(&server, &payload, enc_target),
     };
+    timing.layers[0] = time_now.elapsed();
+
+    let time_now = std::time::Instant::now();
     // Encrypt for node 3
-    let ctx_2 = encrypt_for_relay(&path.node_3, &path.target, &ctx_1);
+    let ctx_2 = encrypt_for_relay(&path.node_3, &path.target, &ctx_1, enc_3);
+    timing.layers[1] = time_now.elapsed();
+
+    let time_now = std::time::Instant::now();
     // Encrypt for node 2
-    let ctx_3 = encrypt_for_relay(&path.node_2, &path.node_3, &ctx_2);
+    let ctx_3 = encrypt_for_relay(&path.node_2, &path.node_3, &ctx_2, enc_2);
+    timing.layers[2] = time_now.elapsed();
+
+    let time_now = std::time::Instant::now();
     // Encrypt for node 1
-    let ctx_4 = encrypt_for_relay(&path.node_1, &path.node_2, &ctx_3);
+    let ctx_4 = encrypt_for_relay(&path.node_1, &path.node_2, &ctx_3, enc_1);
 
     let payload = payload_for_guard_node(&ctx_4);
+    timing.layers[3] = time_now.elapsed();
 
     (payload, ctx_1.secret_key)
 }
@@ -27,6 +52,8 @@ struct EncryptionContext {
     secret_key: Vec<u8>,
     // send this to the peer
     ephemeral_pubkey: ring::agreement::PublicKey,
+    // the peer needs to know this to derive the key
+    enc_type: EncryptionType,
 }
 
 // ctx is the result of previous encryption, it contains the actual ciphertext and the ephemeral key used
@@ -34,10 +61,12 @@ fn encrypt_for_relay(
     relay: &NextHop,
     next_hop: &NextHop,
     ctx: &EncryptionContext,
+    enc_type: EncryptionType,
 ) -> EncryptionContext {
     // let mut payload = serialize_blob(&ctx.ciphertext);
     let mut json_payload = json!({
         "ephemeral_key": hex::encode(&ctx.ephemeral_pubkey),
+        "enc_type": ctx.enc_type.as_str(),
     });
 
     match next_hop {
@@ -67,16 +96,21 @@ fn encrypt_for_relay(
     //     println!("node 3 payload: {:?}", &payload);
     // }
 
-    let (ciphertext, secret_key, ephemeral_pubkey) = ecdh::encrypt_gcm(&relay, &payload);
+    let (ciphertext, secret_key, ephemeral_pubkey) = ecdh::encrypt(&relay, &payload, enc_type);
 
     EncryptionContext {
         ciphertext,
         secret_key,
         ephemeral_pubkey,
+        enc_type,
     }
 }
 
-fn encrypt_for_target_node(sn: &ServiceNode, payload: &[u8]) -> EncryptionContext {
+fn encrypt_for_target_node(
+    sn: &ServiceNode,
+    payload: &[u8],
+    enc_type: EncryptionType,
+) -> EncryptionContext {
     // Yes, this is json around json
 
     let params = json!({
@@ -88,38 +122,55 @@ fn encrypt_for_target_node(sn: &ServiceNode, payload: &[u8]) -> EncryptionContex
     let plaintext = payload;
 
     let (ciphertext, secret_key, ephemeral_pubkey) =
-        ecdh::encrypt_gcm(&NextHop::Node(sn.clone()), &plaintext);
+        ecdh::encrypt(&NextHop::Node(sn.clone()), &plaintext, enc_type);
 
     EncryptionContext {
         ciphertext,
         secret_key,
         ephemeral_pubkey,
+        enc_type,
     }
 }
 
-fn encrypt_for_target_server(server: &LokiServer, payload: &[u8]) -> EncryptionContext {
+fn encrypt_for_target_server(
+    server: &LokiServer,
+    payload: &[u8],
+    enc_type: EncryptionType,
+) -> EncryptionContext {
     let plaintext = std::str::from_utf8(payload).expect("non-utf8");
 
-    let (ciphertext, secret_key, ephemeral_pubkey) =
-        ecdh::encrypt_gcm(&NextHop::Server(server.clone()), plaintext.as_bytes());
+    let (ciphertext, secret_key, ephemeral_pubkey) = ecdh::encrypt(
+        &NextHop::Server(server.clone()),
+        plaintext.as_bytes(),
+        enc_type,
+    );
 
     EncryptionContext {
         ciphertext,
         secret_key,
         ephemeral_pubkey,
+        enc_type,
     }
 }
 
-fn encrypt_for_target_server_v2(server: &LokiServerV2, payload: &[u8]) -> EncryptionContext {
+fn encrypt_for_target_server_v2(
+    server: &LokiServerV2,
+    payload: &[u8],
+    enc_type: EncryptionType,
+) -> EncryptionContext {
     let plaintext = std::str::from_utf8(payload).expect("non-utf8");
 
-    let (ciphertext, secret_key, ephemeral_pubkey) =
-        ecdh::encrypt_gcm(&NextHop::ServerV2(server.clone()), plaintext.as_bytes());
+    let (ciphertext, secret_key, ephemeral_pubkey) = ecdh::encrypt(
+        &NextHop::ServerV2(server.clone()),
+        plaintext.as_bytes(),
+        enc_type,
+    );
 
     EncryptionContext {
         ciphertext,
         secret_key,
         ephemeral_pubkey,
+        enc_type,
     }
 }
 
@@ -132,6 +183,7 @@ fn payload_for_guard_node(ctx: &EncryptionContext) -> Vec<u8> {
 
     let json = json!({
         "ephemeral_key": hex::encode(&ctx.ephemeral_pubkey),
+        "enc_type": ctx.enc_type.as_str(),
     });
 
     serialized_combined(&ctx.ciphertext, json)
@@ -158,3 +210,289 @@ fn serialize_blob(blob: &[u8]) -> Vec<u8> {
 
     result
 }
+
+/// Where a node is told to send the payload after peeling its layer
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayerDestination {
+    /// Relay to another service node (identified by its ed25519 key)
+    Node {
+        pubkey_ed25519: String,
+        ephemeral_key: Vec<u8>,
+        enc_type: EncryptionType,
+    },
+    /// Relay to a Loki server (port and protocol are only set for `ServerV2`)
+    Server {
+        host: String,
+        target: String,
+        port: Option<u16>,
+        protocol: Option<String>,
+        ephemeral_key: Vec<u8>,
+        enc_type: EncryptionType,
+    },
+    /// This node is the target, the payload is the actual request
+    Target,
+}
+
+/// The result of peeling one layer off an onion
+#[derive(Debug)]
+pub struct OnionLayer {
+    /// Ciphertext for the next hop, or the request itself if we are the target
+    pub payload: Vec<u8>,
+    pub destination: LayerDestination,
+    /// Key shared with the client, the target uses it to encrypt the response
+    pub symmetric_key: Vec<u8>,
+    /// How this layer was encrypted, the target responds with the same cipher
+    pub enc_type: EncryptionType,
+}
+
+impl OnionLayer {
+    /// What this node would send to the next hop (same format as for the guard node)
+    pub fn forward_payload(&self) -> Option<Vec<u8>> {
+        let (ephemeral_key, enc_type) = match &self.destination {
+            LayerDestination::Node {
+                ephemeral_key,
+                enc_type,
+                ..
+            } => (ephemeral_key, enc_type),
+            LayerDestination::Server {
+                ephemeral_key,
+                enc_type,
+                ..
+            } => (ephemeral_key, enc_type),
+            LayerDestination::Target => return None,
+        };
+
+        let json = json!({
+            "ephemeral_key": hex::encode(ephemeral_key),
+            "enc_type": enc_type.as_str(),
+        });
+
+        Some(serialized_combined(&self.payload, json))
+    }
+}
+
+/// Reverse of `serialized_combined`: split into the blob and the json that follows it
+pub fn parse_combined(data: &[u8]) -> Result<(Vec<u8>, serde_json::Value), String> {
+    if data.len() < 4 {
+        return Err(format!("Payload too short: {}", data.len()));
+    }
+
+    let size = LittleEndian::read_u32(&data[0..4]) as usize;
+
+    if data.len() - 4 < size {
+        return Err(format!(
+            "Invalid blob size: {}, only {} bytes left",
+            size,
+            data.len() - 4
+        ));
+    }
+
+    let blob = data[4..4 + size].to_vec();
+
+    let json = serde_json::from_slice(&data[4 + size..])
+        .map_err(|err| format!("Invalid json after blob: {}", err))?;
+
+    Ok((blob, json))
+}
+
+fn get_ephemeral_key(json: &serde_json::Value) -> Result<Vec<u8>, String> {
+    let key = json["ephemeral_key"]
+        .as_str()
+        .ok_or("No ephemeral_key in json")?;
+
+    hex::decode(key).map_err(|_| "ephemeral_key is not hex".to_owned())
+}
+
+/// Service nodes assume AES-GCM when `enc_type` is missing
+fn get_enc_type(json: &serde_json::Value) -> Result<EncryptionType, String> {
+    match json["enc_type"].as_str() {
+        Some(enc_type) => enc_type.parse(),
+        None => Ok(EncryptionType::AesGcm),
+    }
+}
+
+/// Peel one layer as a service node with x25519 secret key `seckey` would
+/// (`data` is what the node receives over http)
+pub fn decrypt_layer(seckey: &[u8; 32], data: &[u8]) -> Result<OnionLayer, String> {
+    let (ciphertext, json) = parse_combined(data)?;
+
+    let ephemeral_key = get_ephemeral_key(&json)?;
+
+    let enc_type = get_enc_type(&json)?;
+
+    let symmetric_key = ecdh::derive_symmetric_key(seckey, &ephemeral_key, enc_type)
+        .ok_or("Could not derive symmetric key")?;
+
+    let plaintext = ecdh::decrypt_bytes(enc_type, &ciphertext, &symmetric_key)
+        .ok_or("Could not decrypt ciphertext")?;
+
+    let (payload, json) = parse_combined(&plaintext)?;
+
+    let destination = if let Some(dest) = json["destination"].as_str() {
+        LayerDestination::Node {
+            pubkey_ed25519: dest.to_owned(),
+            ephemeral_key: get_ephemeral_key(&json)?,
+            enc_type: get_enc_type(&json)?,
+        }
+    } else if let Some(host) = json["host"].as_str() {
+        let target = json["target"].as_str().ok_or("No target for host")?;
+
+        LayerDestination::Server {
+            host: host.to_owned(),
+            target: target.to_owned(),
+            port: json["port"].as_u64().map(|port| port as u16),
+            protocol: json["protocol"].as_str().map(|p| p.to_owned()),
+            ephemeral_key: get_ephemeral_key(&json)?,
+            enc_type: get_enc_type(&json)?,
+        }
+    } else if json.get("headers").is_some() {
+        LayerDestination::Target
+    } else {
+        return Err(format!("Unexpected layer json: {}", json));
+    };
+
+    Ok(OnionLayer {
+        payload,
+        destination,
+        symmetric_key,
+        enc_type,
+    })
+}
+
+/// Encrypt the response at the target the way `sn_api::onion_request_v2` expects it
+pub fn encrypt_response(layer: &OnionLayer, body: &[u8]) -> String {
+    base64::encode(ecdh::encrypt_bytes(
+        layer.enc_type,
+        body,
+        &layer.symmetric_key,
+    ))
+}
+
+#[cfg(test)]
+pub(super) fn test_node(idx: u8, pubkey_x25519: &[u8; 32]) -> ServiceNode {
+    ServiceNode {
+        public_ip: "127.0.0.1".to_owned(),
+        storage_port: 8080 + idx as u16,
+        storage_lmq_port: 0,
+        service_node_pubkey: "".to_owned(),
+        operator_address: "".to_owned(),
+        pubkey_x25519: hex::encode(pubkey_x25519),
+        pubkey_ed25519: hex::encode([idx; 32]),
+        swarm_id: 0,
+        plain_http: false,
+    }
+}
+
+#[test]
+fn test_decrypt_all_layers() {
+    let keys: Vec<_> = (0..4).map(|_| ecdh::gen_static_keypair()).collect();
+
+    let nodes: Vec<_> = keys
+        .iter()
+        .enumerate()
+        .map(|(idx, (_, pk))| test_node(idx as u8, pk))
+        .collect();
+
+    let path = OnionPath {
+        node_1: NextHop::Node(nodes[0].clone()),
+        node_2: NextHop::Node(nodes[1].clone()),
+        node_3: NextHop::Node(nodes[2].clone()),
+        target: NextHop::Node(nodes[3].clone()),
+    };
+
+    let enc_types = [
+        EncryptionType::AesGcm,
+        EncryptionType::XChaCha20,
+        EncryptionType::AesGcm,
+        EncryptionType::XChaCha20,
+    ];
+
+    let mut timing = OnionTiming::default();
+
+    let (mut data, decryption_key) = futures::executor::block_on(onion_request(
+        &path,
+        b"test payload",
+        enc_types,
+        &mut timing,
+    ));
+
+    assert!(timing.layers.iter().all(|t| *t > std::time::Duration::from_secs(0)));
+
+    for idx in 0..3 {
+        let layer = decrypt_layer(&keys[idx].0, &data).expect("Could not decrypt layer");
+
+        assert_eq!(layer.enc_type, enc_types[idx]);
+
+        match &layer.destination {
+            LayerDestination::Node { pubkey_ed25519, .. } => {
+                assert_eq!(pubkey_ed25519, &nodes[idx + 1].pubkey_ed25519);
+            }
+            dest => panic!("Unexpected destination: {:?}", dest),
+        }
+
+        data = layer.forward_payload().unwrap();
+    }
+
+    // Wrong key must fail
+    assert!(decrypt_layer(&keys[0].0, &data).is_err());
+
+    let layer = decrypt_layer(&keys[3].0, &data).expect("Could not decrypt target layer");
+
+    assert_eq!(layer.destination, LayerDestination::Target);
+    assert_eq!(layer.payload, b"test payload");
+    assert_eq!(layer.symmetric_key, decryption_key);
+    assert_eq!(layer.enc_type, EncryptionType::XChaCha20);
+
+    let response = encrypt_response(&layer, b"test response");
+
+    // The response comes back with the target's cipher
+    assert!(ecdh::aes_gcm_decrypt(response.clone(), &decryption_key).is_none());
+
+    assert_eq!(
+        ecdh::decrypt(EncryptionType::XChaCha20, response, &decryption_key).unwrap(),
+        "test response"
+    );
+}
+
+#[test]
+fn test_decrypt_server_layer() {
+    let (seckey, pubkey) = ecdh::gen_static_keypair();
+
+    let relay = NextHop::Node(test_node(0, &pubkey));
+
+    let server = NextHop::ServerV2(LokiServerV2 {
+        host: "example.org".to_owned(),
+        port: 80,
+        target: "/loki/v3/lsrpc".to_owned(),
+        protocol: "http".to_owned(),
+        pubkey_x25519: hex::encode(pubkey),
+    });
+
+    let inner = encrypt_for_relay(
+        &server,
+        &server,
+        &encrypt_for_target_node(&test_node(1, &pubkey), b"", EncryptionType::AesGcm),
+        EncryptionType::XChaCha20,
+    );
+    let outer = encrypt_for_relay(&relay, &server, &inner, EncryptionType::AesGcm);
+
+    let layer = decrypt_layer(&seckey, &payload_for_guard_node(&outer)).unwrap();
+
+    match layer.destination {
+        LayerDestination::Server {
+            host,
+            port,
+            protocol,
+            enc_type,
+            ..
+        } => {
+            assert_eq!(host, "example.org");
+            assert_eq!(port, Some(80));
+            assert_eq!(protocol.as_deref(), Some("http"));
+            assert_eq!(enc_type, EncryptionType::XChaCha20);
+        }
+        dest => panic!("Unexpected destination: {:?}", dest),
+    }
+
+    assert_eq!(layer.payload, inner.ciphertext);
+}
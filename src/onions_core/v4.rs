@@ -0,0 +1,181 @@
+use serde_json::json;
+
+use crate::{
+    ecdh::EncryptionType,
+    loki::{LokiServer, LokiServerV2},
+    onions::{NextHop, OnionErrorKind, OnionPath, OnionTiming},
+};
+
+/// Servers take v4 requests here rather than at their v3 endpoint
+pub const SERVER_V4_ENDPOINT: &str = "/oxen/v4/lsrpc";
+
+/// Layers are built the same way as in v2, only the request for the target is
+/// a bencoded `[metadata json, body]` pair instead of json, and the target's
+/// response is a binary `[{"code": <status>, ...}, body]` pair.
+pub async fn onion_request(
+    path: &OnionPath,
+    payload: &[u8],
+    enc_types: [EncryptionType; 4],
+    timing: &mut OnionTiming,
+) -> Result<(Vec<u8>, Vec<u8>), OnionErrorKind> {
+    let mut path = path.clone();
+
+    let request = match &mut path.target {
+        NextHop::Node(_) => {
+            let meta = json!({
+                "method": "POST",
+                "endpoint": "/storage_rpc/v1",
+                "headers": {},
+            });
+
+            bencode_pair(meta.to_string().as_bytes(), payload)
+        }
+        NextHop::Server(LokiServer { target, .. })
+        | NextHop::ServerV2(LokiServerV2 { target, .. }) => {
+            *target = SERVER_V4_ENDPOINT.to_owned();
+
+            // Server payloads are json with the body embedded, move the body out of it
+            let mut meta: serde_json::Value = serde_json::from_slice(payload).map_err(|_| {
+                OnionErrorKind::InvalidRequest("Server payload must be json".to_owned())
+            })?;
+
+            let body = match meta.as_object_mut().and_then(|m| m.remove("body")) {
+                Some(serde_json::Value::String(body)) => body,
+                Some(body) => body.to_string(),
+                None => "".to_owned(),
+            };
+
+            bencode_pair(meta.to_string().as_bytes(), body.as_bytes())
+        }
+    };
+
+    Ok(super::v2::onion_request(&path, &request, enc_types, timing).await)
+}
+
+/// Encode as a bencoded list of two byte strings
+pub fn bencode_pair(first: &[u8], second: &[u8]) -> Vec<u8> {
+    let mut res = vec![b'l'];
+
+    for item in &[first, second] {
+        res.extend_from_slice(format!("{}:", item.len()).as_bytes());
+        res.extend_from_slice(item);
+    }
+
+    res.push(b'e');
+
+    res
+}
+
+/// Reverse of `bencode_pair`
+pub fn parse_bencoded_pair(data: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    if data.first() != Some(&b'l') || data.last() != Some(&b'e') {
+        return Err("Not a bencoded list".to_owned());
+    }
+
+    let mut rest = &data[1..data.len() - 1];
+
+    let mut items = vec![];
+
+    while !rest.is_empty() {
+        let colon = rest
+            .iter()
+            .position(|b| *b == b':')
+            .ok_or("No length prefix")?;
+
+        let len: usize = std::str::from_utf8(&rest[..colon])
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .ok_or("Invalid length prefix")?;
+
+        rest = &rest[colon + 1..];
+
+        if rest.len() < len {
+            return Err(format!("String length {} exceeds the data", len));
+        }
+
+        items.push(rest[..len].to_vec());
+
+        rest = &rest[len..];
+    }
+
+    if items.len() != 2 {
+        return Err(format!("Expected 2 items, got {}", items.len()));
+    }
+
+    let second = items.pop().unwrap();
+    let first = items.pop().unwrap();
+
+    Ok((first, second))
+}
+
+#[test]
+fn test_bencode_pair() {
+    let encoded = bencode_pair(br#"{"code":200}"#, b"l5:helloe");
+
+    assert_eq!(encoded, b"l12:{\"code\":200}9:l5:helloee".to_vec());
+
+    let (first, second) = parse_bencoded_pair(&encoded).unwrap();
+
+    assert_eq!(first, br#"{"code":200}"#);
+    assert_eq!(second, b"l5:helloe");
+
+    assert!(parse_bencoded_pair(b"l5:helloe").is_err());
+    assert!(parse_bencoded_pair(b"l50:helloe").is_err());
+}
+
+#[test]
+fn test_server_requests_go_to_v4_endpoint() {
+    use super::v2::{decrypt_layer, test_node, LayerDestination};
+    use crate::ecdh;
+
+    let keys: Vec<_> = (0..3).map(|_| ecdh::gen_static_keypair()).collect();
+
+    let nodes: Vec<_> = keys
+        .iter()
+        .enumerate()
+        .map(|(idx, (_, pk))| NextHop::Node(test_node(idx as u8, pk)))
+        .collect();
+
+    let path = OnionPath {
+        node_1: nodes[0].clone(),
+        node_2: nodes[1].clone(),
+        node_3: nodes[2].clone(),
+        target: NextHop::ServerV2(LokiServerV2 {
+            host: "example.org".to_owned(),
+            port: 80,
+            target: "/loki/v3/lsrpc".to_owned(),
+            protocol: "http".to_owned(),
+            pubkey_x25519: hex::encode(ecdh::gen_static_keypair().1),
+        }),
+    };
+
+    let enc_types = [EncryptionType::AesGcm; 4];
+
+    let mut timing = OnionTiming::default();
+
+    let request = futures::executor::block_on(onion_request(
+        &path,
+        br#"{"method": "GET", "body": ""}"#,
+        enc_types,
+        &mut timing,
+    ));
+
+    let (mut data, _) = request.unwrap();
+
+    for (seckey, _) in &keys[..2] {
+        data = decrypt_layer(seckey, &data)
+            .unwrap()
+            .forward_payload()
+            .unwrap();
+    }
+
+    match decrypt_layer(&keys[2].0, &data).unwrap().destination {
+        LayerDestination::Server { target, .. } => assert_eq!(target, SERVER_V4_ENDPOINT),
+        dest => panic!("Unexpected destination: {:?}", dest),
+    }
+
+    let request =
+        futures::executor::block_on(onion_request(&path, b"not json", enc_types, &mut timing));
+
+    assert!(matches!(request, Err(OnionErrorKind::InvalidRequest(_))));
+}
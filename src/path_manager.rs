@@ -0,0 +1,279 @@
+use std::collections::HashSet;
+
+use crate::{
+    loki::ServiceNode,
+    node_pool::NodePool,
+    onions::{NextHop, OnionError, OnionErrorKind},
+};
+
+struct ManagedPath {
+    nodes: [ServiceNode; 3],
+    consecutive_failures: u32,
+}
+
+/// Keeps a few paths that work and sticks to them, instead of building a new one
+/// for every request. Each path has its own guard node, which is only replaced
+/// when the guard itself is to blame for the failures.
+pub struct PathManager {
+    n_paths: usize,
+    /// Retire a path after this many failures in a row
+    max_failures: u32,
+    paths: Vec<ManagedPath>,
+    /// Guards of retired paths that are still good to use
+    spare_guards: Vec<ServiceNode>,
+    /// Ed25519 keys of guards that caused their path to be retired, never picked again
+    failed_guards: HashSet<String>,
+    /// Used to rotate through paths
+    next_path: usize,
+}
+
+impl PathManager {
+    pub fn new(n_paths: usize, max_failures: u32) -> Self {
+        PathManager {
+            n_paths,
+            max_failures,
+            paths: vec![],
+            spare_guards: vec![],
+            failed_guards: HashSet::new(),
+            next_path: 0,
+        }
+    }
+
+    /// Next path in rotation that can be used for `target`, building missing
+    /// paths from `node_pool` first. Fails if there aren't enough nodes for any path.
+    pub fn get_path(
+        &mut self,
+        node_pool: &mut NodePool,
+        target: &NextHop,
+    ) -> Result<[ServiceNode; 3], String> {
+        let excluded = node_pool.excluded_target(target);
+
+        let skip = |n: &ServiceNode| Some(&n.pubkey_ed25519) == excluded.as_ref();
+
+        while self.paths.len() < self.n_paths {
+            let guard = self
+                .spare_guards
+                .iter()
+                .position(|g| !skip(g))
+                .map(|idx| self.spare_guards.remove(idx));
+
+            match self.build_path(node_pool, guard, skip) {
+                Some(nodes) => self.paths.push(ManagedPath {
+                    nodes,
+                    consecutive_failures: 0,
+                }),
+                None => break,
+            }
+        }
+
+        let n_paths = self.paths.len();
+
+        let idx = (0..n_paths)
+            .map(|i| (self.next_path + i) % n_paths)
+            .find(|idx| !self.paths[*idx].nodes.iter().any(skip));
+
+        self.next_path = self.next_path.wrapping_add(1);
+
+        match idx {
+            Some(idx) => Ok(self.paths[idx].nodes.clone()),
+            // Every path goes through the target (or there are no paths), use a new
+            // one just this once
+            None => self
+                .build_path(node_pool, None, skip)
+                .ok_or_else(|| "Not enough nodes to build a path".to_owned()),
+        }
+    }
+
+    pub fn report_success(&mut self, path: &[ServiceNode; 3]) {
+        if let Some(idx) = self.find(path) {
+            self.paths[idx].consecutive_failures = 0;
+        }
+    }
+
+    /// Retire the path once it failed `max_failures` times in a row. Its guard
+    /// is reused for the replacement unless the last error was the guard's fault.
+    /// Errors of the target say nothing about the path and are ignored.
+    pub fn report_failure(&mut self, path: &[ServiceNode; 3], err: &OnionError) {
+        let target_failed = matches!(err.kind, OnionErrorKind::Target { .. })
+            || matches!(
+                (err.culprit(), &err.path.target),
+                (Some(NextHop::Node(culprit)), NextHop::Node(target))
+                    if culprit.pubkey_ed25519 == target.pubkey_ed25519
+            );
+
+        if target_failed {
+            return;
+        }
+
+        let idx = match self.find(path) {
+            Some(idx) => idx,
+            None => return,
+        };
+
+        self.paths[idx].consecutive_failures += 1;
+
+        if self.paths[idx].consecutive_failures < self.max_failures {
+            return;
+        }
+
+        let retired = self.paths.remove(idx);
+
+        let guard_failed = matches!(
+            err.culprit(),
+            Some(NextHop::Node(n)) if n.pubkey_ed25519 == retired.nodes[0].pubkey_ed25519
+        );
+
+        let [guard, _, _] = retired.nodes;
+
+        if guard_failed {
+            self.failed_guards.insert(guard.pubkey_ed25519);
+        } else {
+            self.spare_guards.push(guard);
+        }
+    }
+
+    pub fn guards(&self) -> Vec<&ServiceNode> {
+        self.paths.iter().map(|p| &p.nodes[0]).collect()
+    }
+
+    fn find(&self, path: &[ServiceNode; 3]) -> Option<usize> {
+        self.paths.iter().position(|p| {
+            p.nodes
+                .iter()
+                .zip(path.iter())
+                .all(|(a, b)| a.pubkey_ed25519 == b.pubkey_ed25519)
+        })
+    }
+
+    /// Nodes already in use can't be picked again, so paths don't share nodes
+    fn build_path<F>(
+        &self,
+        node_pool: &mut NodePool,
+        guard: Option<ServiceNode>,
+        skip: F,
+    ) -> Option<[ServiceNode; 3]>
+    where
+        F: Fn(&ServiceNode) -> bool,
+    {
+        let in_use = |n: &ServiceNode| {
+            self.paths
+                .iter()
+                .flat_map(|p| p.nodes.iter())
+                .chain(self.spare_guards.iter())
+                .any(|used| used.pubkey_ed25519 == n.pubkey_ed25519)
+        };
+
+        let fixed: Vec<ServiceNode> = guard.into_iter().collect();
+
+        node_pool.build_path(&fixed, |n| {
+            skip(n) || in_use(n) || self.failed_guards.contains(&n.pubkey_ed25519)
+        })
+    }
+}
+
+#[test]
+fn test_paths_are_reused_and_retired() {
+    use crate::{
+        node_pool::{test_nodes, PathConstraints},
+        onions::OnionPath,
+    };
+
+    let mut node_pool = NodePool::from_nodes(test_nodes(20));
+
+    let mut manager = PathManager::new(3, 2);
+
+    let target_node = test_nodes(21).pop().unwrap();
+    let target = NextHop::Node(target_node.clone());
+
+    let paths: Vec<_> = (0..3)
+        .map(|_| manager.get_path(&mut node_pool, &target).unwrap())
+        .collect();
+
+    let guards: Vec<String> = paths.iter().map(|p| p[0].pubkey_ed25519.clone()).collect();
+
+    // Paths are handed out in rotation
+    let again = manager.get_path(&mut node_pool, &target).unwrap();
+    assert_eq!(again[0].pubkey_ed25519, guards[0]);
+
+    let error = |path: &[ServiceNode; 3], kind| OnionError {
+        kind,
+        path: OnionPath {
+            node_1: NextHop::Node(path[0].clone()),
+            node_2: NextHop::Node(path[1].clone()),
+            node_3: NextHop::Node(path[2].clone()),
+            target: target.clone(),
+        },
+    };
+
+    // The target failing says nothing about the path
+    let rejected = error(
+        &paths[0],
+        OnionErrorKind::Target {
+            status: 421,
+            body: "".to_owned(),
+        },
+    );
+    let target_not_found = error(
+        &paths[0],
+        OnionErrorKind::NextNodeNotFound(target_node.pubkey_ed25519),
+    );
+
+    for _ in 0..3 {
+        manager.report_failure(&paths[0], &rejected);
+        manager.report_failure(&paths[0], &target_not_found);
+    }
+    assert_eq!(manager.guards().len(), 3);
+
+    let decryption = error(&paths[0], OnionErrorKind::Decryption);
+
+    // A success in between resets the count
+    manager.report_failure(&paths[0], &decryption);
+    manager.report_success(&paths[0]);
+    manager.report_failure(&paths[0], &decryption);
+    assert_eq!(manager.guards().len(), 3);
+
+    manager.report_failure(&paths[0], &decryption);
+    assert_eq!(manager.guards().len(), 2);
+
+    // The guard wasn't to blame, so the replacement path keeps it
+    assert!((0..3)
+        .map(|_| manager.get_path(&mut node_pool, &target).unwrap())
+        .any(|p| p[0].pubkey_ed25519 == guards[0]));
+
+    // A guard that can't be reached gets replaced
+    let unreachable = error(
+        &paths[1],
+        OnionErrorKind::GuardTransport("refused".to_owned()),
+    );
+
+    manager.report_failure(&paths[1], &unreachable);
+    manager.report_failure(&paths[1], &unreachable);
+
+    for _ in 0..3 {
+        let path = manager.get_path(&mut node_pool, &target).unwrap();
+        assert!(path.iter().all(|n| n.pubkey_ed25519 != guards[1]));
+    }
+
+    assert_eq!(manager.guards().len(), 3);
+
+    // Paths through a node aren't used for requests to it
+    node_pool.set_path_constraints(PathConstraints {
+        exclude_target: true,
+        ..Default::default()
+    });
+
+    let busy = manager.get_path(&mut node_pool, &target).unwrap()[1].clone();
+    let target = NextHop::Node(busy.clone());
+
+    for _ in 0..3 {
+        let path = manager.get_path(&mut node_pool, &target).unwrap();
+        assert!(path.iter().all(|n| n.pubkey_ed25519 != busy.pubkey_ed25519));
+    }
+
+    // Too few nodes for any path is an error rather than a panic
+    let mut small_pool = NodePool::from_nodes(test_nodes(2));
+
+    assert!(PathManager::new(3, 2)
+        .get_path(&mut small_pool, &target)
+        .is_err());
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{loki::ServiceNode, node_pool::NodeReputation};
+
+/// Last known state of the node pool, used when no seed can be reached
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PoolSnapshot {
+    /// Unix time in seconds
+    pub saved_at: u64,
+    pub nodes: Vec<ServiceNode>,
+    /// By ed25519 key
+    #[serde(default)]
+    pub reputation: HashMap<String, NodeReputation>,
+}
+
+impl PoolSnapshot {
+    pub fn new(nodes: Vec<ServiceNode>, reputation: HashMap<String, NodeReputation>) -> Self {
+        let saved_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Could not get UNIX time")
+            .as_secs();
+
+        PoolSnapshot {
+            saved_at,
+            nodes,
+            reputation,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+
+        serde_json::from_str(&data).map_err(|err| format!("Invalid pool snapshot: {}", err))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let data = serde_json::to_string(self).expect("Could not serialize pool snapshot");
+
+        // Write to a temporary file first so a crash can't leave a half written snapshot
+        let tmp = path.with_extension("tmp");
+
+        std::fs::write(&tmp, data)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|err| format!("Could not write {}: {}", path.display(), err))
+    }
+}
+
+/// What changed between two lists of nodes. Nodes are matched by their
+/// service node pubkey, so that nodes with new ed25519/x25519 keys are still recognised.
+#[derive(Debug, Clone, Default)]
+pub struct PoolDiff {
+    pub joined: Vec<ServiceNode>,
+    pub left: Vec<ServiceNode>,
+    /// (before, after)
+    pub changed_ip: Vec<(ServiceNode, ServiceNode)>,
+    /// (before, after)
+    pub changed_keys: Vec<(ServiceNode, ServiceNode)>,
+}
+
+/// Nodes learned from a swarm don't come with a service node pubkey
+pub fn node_id(node: &ServiceNode) -> &str {
+    if node.service_node_pubkey.is_empty() {
+        &node.pubkey_ed25519
+    } else {
+        &node.service_node_pubkey
+    }
+}
+
+impl PoolDiff {
+    pub fn new(before: &[ServiceNode], after: &[ServiceNode]) -> Self {
+        let before_by_id: HashMap<&str, &ServiceNode> =
+            before.iter().map(|n| (node_id(n), n)).collect();
+
+        let after_by_id: HashMap<&str, &ServiceNode> =
+            after.iter().map(|n| (node_id(n), n)).collect();
+
+        let mut diff = PoolDiff::default();
+
+        for node in after {
+            let old = match before_by_id.get(node_id(node)) {
+                Some(old) => *old,
+                None => {
+                    diff.joined.push(node.clone());
+                    continue;
+                }
+            };
+
+            if old.public_ip != node.public_ip || old.storage_port != node.storage_port {
+                diff.changed_ip.push((old.clone(), node.clone()));
+            }
+
+            if old.pubkey_ed25519 != node.pubkey_ed25519 || old.pubkey_x25519 != node.pubkey_x25519
+            {
+                diff.changed_keys.push((old.clone(), node.clone()));
+            }
+        }
+
+        diff.left = before
+            .iter()
+            .filter(|n| !after_by_id.contains_key(node_id(n)))
+            .cloned()
+            .collect();
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.joined.is_empty()
+            && self.left.is_empty()
+            && self.changed_ip.is_empty()
+            && self.changed_keys.is_empty()
+    }
+
+    pub fn print(&self) {
+        if self.is_empty() {
+            println!("Pool changes: none");
+            return;
+        }
+
+        for node in &self.joined {
+            println!("Node joined: {} ({})", node, node_id(node));
+        }
+
+        for node in &self.left {
+            println!("Node left: {} ({})", node, node_id(node));
+        }
+
+        for (old, new) in &self.changed_ip {
+            println!("Node changed ip: {} -> {} ({})", old, new, node_id(new));
+        }
+
+        for (old, new) in &self.changed_keys {
+            println!(
+                "Node changed keys: {} ed25519 {} -> {}, x25519 {} -> {}",
+                node_id(new),
+                old.pubkey_ed25519,
+                new.pubkey_ed25519,
+                old.pubkey_x25519,
+                new.pubkey_x25519
+            );
+        }
+
+        println!(
+            "Pool changes: {} joined, {} left, {} changed ip, {} changed keys",
+            self.joined.len(),
+            self.left.len(),
+            self.changed_ip.len(),
+            self.changed_keys.len()
+        );
+    }
+}
+
+#[test]
+fn test_pool_diff() {
+    use crate::node_pool::test_nodes;
+
+    let before = test_nodes(5);
+
+    let mut after = before[1..].to_vec();
+
+    after[0].public_ip = "10.0.0.1".to_owned();
+    after[1].pubkey_x25519 = "ff".repeat(32);
+    after.extend(test_nodes(6).pop());
+
+    let diff = PoolDiff::new(&before, &after);
+
+    assert_eq!(diff.left.len(), 1);
+    assert_eq!(diff.left[0].pubkey_ed25519, before[0].pubkey_ed25519);
+    assert_eq!(diff.joined.len(), 1);
+    assert_eq!(diff.changed_ip.len(), 1);
+    assert_eq!(diff.changed_ip[0].1.public_ip, "10.0.0.1");
+    assert_eq!(diff.changed_keys.len(), 1);
+    assert_eq!(
+        diff.changed_keys[0].0.pubkey_ed25519,
+        before[2].pubkey_ed25519
+    );
+
+    assert!(PoolDiff::new(&after, &after).is_empty());
+}
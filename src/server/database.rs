@@ -1,13 +1,32 @@
 use std::{
+    path::Path,
     sync::{Arc, Mutex},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use rusqlite::{params, Connection, NO_PARAMS};
+use rusqlite::{params, Connection, OpenFlags, NO_PARAMS};
 
 use log::error;
 
-use super::OnionResultAggregated;
+use crate::{
+    inference::Observation,
+    pool_snapshot::{node_id, PoolDiff},
+    triage::Verdict,
+};
+
+use super::{
+    BrokenHop, DeliveryAggregated, DeliveryResult, KindMinute, NodeMinute, NodeStats, OnionResult,
+    OnionResultAggregated, PoolChurn, RoleStats,
+};
+
+/// Per node stats are over this long
+const NODE_STATS_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Requests, probes and per minute results older than this are deleted
+const RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Delivery probes are aggregated over windows this long
+const DELIVERY_WINDOW: Duration = Duration::from_secs(10 * 60);
 
 const DB_NAME: &'static str = "data.db";
 
@@ -34,11 +53,77 @@ impl ResultsDb {
         let connection = self.connection.lock().unwrap();
         get_entries(&connection)
     }
+
+    pub(super) fn add_requests(&self, results: &[OnionResult]) {
+        let mut connection = self.connection.lock().unwrap();
+        add_requests(&mut connection, results);
+    }
+
+    pub(super) fn prune(&self) {
+        let connection = self.connection.lock().unwrap();
+        prune(&connection, SystemTime::now() - RETENTION);
+    }
+
+    pub(super) fn read_node_stats(&self) -> Vec<NodeStats> {
+        let connection = self.connection.lock().unwrap();
+        get_node_stats(&connection, None)
+    }
+
+    pub(super) fn read_node_stats_for(&self, pubkey: &str) -> Option<NodeStats> {
+        let connection = self.connection.lock().unwrap();
+        get_node_stats(&connection, Some(pubkey)).pop()
+    }
+
+    pub(super) fn read_node_minutes(&self, pubkey: &str) -> Vec<NodeMinute> {
+        let connection = self.connection.lock().unwrap();
+        get_node_minutes(&connection, pubkey)
+    }
+
+    pub(super) fn add_delivery(&self, res: &DeliveryResult) {
+        let connection = self.connection.lock().unwrap();
+        add_delivery(&connection, res);
+    }
+
+    pub(super) fn read_delivery(&self) -> Vec<DeliveryAggregated> {
+        let connection = self.connection.lock().unwrap();
+        get_delivery(&connection)
+    }
+
+    pub(super) fn read_kind_minutes(&self) -> Vec<KindMinute> {
+        let connection = self.connection.lock().unwrap();
+        get_kind_minutes(&connection)
+    }
+
+    pub(super) fn add_triage(&self, time: SystemTime, verdict: &Verdict) {
+        let connection = self.connection.lock().unwrap();
+        add_triage(&connection, time, verdict);
+    }
+
+    pub(super) fn read_broken_hops(&self) -> Vec<BrokenHop> {
+        let connection = self.connection.lock().unwrap();
+        get_broken_hops(&connection)
+    }
+
+    pub(super) fn add_churn(&self, time: SystemTime, total: usize, diff: &PoolDiff) {
+        let mut connection = self.connection.lock().unwrap();
+        add_churn(&mut connection, time, total, diff);
+    }
+
+    pub(super) fn read_churn(&self) -> Vec<PoolChurn> {
+        let connection = self.connection.lock().unwrap();
+        get_churn(&connection)
+    }
 }
 
 pub fn create_or_open() -> Connection {
     let db = Connection::open(DB_NAME).expect("Could not open the DB");
 
+    create_tables(&db);
+
+    db
+}
+
+fn create_tables(db: &Connection) {
     db.execute(
         "CREATE TABLE IF NOT EXISTS onion_results(
         timestamp TEXT NOT NULL PRIMARY KEY,
@@ -49,7 +134,468 @@ pub fn create_or_open() -> Connection {
     )
     .expect("could not create or open DB");
 
-    db
+    // Every onion request with its path (and what probing failed paths found),
+    // the same results per node (in the role it had on the path) and per request
+    // kind per minute, store -> retrieve round trips, and the node pool changes
+    // on every refresh
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS pool_refreshes(
+        timestamp TEXT NOT NULL PRIMARY KEY,
+        total INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS onion_requests(
+        timestamp TEXT NOT NULL,
+        guard TEXT NOT NULL,
+        relay_1 TEXT NOT NULL,
+        relay_2 TEXT NOT NULL,
+        target TEXT NOT NULL,
+        success INTEGER NOT NULL,
+        error TEXT,
+        culprit TEXT,
+        kind TEXT NOT NULL,
+        version TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS delivery_probes(
+        timestamp TEXT NOT NULL,
+        stored INTEGER NOT NULL,
+        latency_ms INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS path_triage(
+        timestamp TEXT NOT NULL,
+        verdict TEXT NOT NULL,
+        node TEXT,
+        next_node TEXT,
+        check_name TEXT
+    );
+    CREATE TABLE IF NOT EXISTS node_results(
+        minute TEXT NOT NULL,
+        node TEXT NOT NULL,
+        role TEXT NOT NULL,
+        total INTEGER NOT NULL,
+        successful INTEGER NOT NULL,
+        PRIMARY KEY (minute, node, role)
+    );
+    CREATE INDEX IF NOT EXISTS node_results_node ON node_results(node);
+    CREATE TABLE IF NOT EXISTS kind_results(
+        minute TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        total INTEGER NOT NULL,
+        successful INTEGER NOT NULL,
+        PRIMARY KEY (minute, kind)
+    );
+    CREATE TABLE IF NOT EXISTS node_churn(
+        timestamp TEXT NOT NULL REFERENCES pool_refreshes(timestamp),
+        node TEXT NOT NULL,
+        change TEXT NOT NULL,
+        before TEXT,
+        after TEXT
+    );",
+    )
+    .expect("could not create or open DB");
+}
+
+fn to_timestamp(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .expect("Could not get UNIX time")
+        .as_millis()
+        .to_string()
+}
+
+fn from_timestamp(timestamp: &str) -> SystemTime {
+    let ms = timestamp.parse::<u64>().unwrap();
+
+    SystemTime::UNIX_EPOCH
+        .checked_add(Duration::from_millis(ms))
+        .unwrap()
+}
+
+/// Delete the per request (and per minute) rows from before `before`
+fn prune(db: &Connection, before: SystemTime) {
+    let tables = [
+        ("onion_requests", "timestamp"),
+        ("path_triage", "timestamp"),
+        ("delivery_probes", "timestamp"),
+        ("node_results", "minute"),
+        ("kind_results", "minute"),
+    ];
+
+    for (table, column) in &tables {
+        let res = db.execute(
+            &format!(
+                "DELETE FROM {} WHERE CAST({} AS INTEGER) < CAST(?1 AS INTEGER)",
+                table, column
+            ),
+            params![to_timestamp(before)],
+        );
+
+        if let Err(err) = res {
+            error!("Could not prune {}: {}", table, err);
+        }
+    }
+}
+
+/// Record what probing a failed path blamed the failure on
+fn add_triage(db: &Connection, time: SystemTime, verdict: &Verdict) {
+    let (node, next_node, check) = match verdict {
+        Verdict::Node { pubkey, check } => (Some(pubkey), None, Some(check.name())),
+        Verdict::Link { from, to } => (Some(from), Some(to), None),
+        Verdict::Inconclusive => (None, None, None),
+    };
+
+    db.execute(
+        "INSERT INTO path_triage (timestamp, verdict, node, next_node, check_name) values (?1, ?2, ?3, ?4, ?5)",
+        params![to_timestamp(time), verdict.kind(), node, next_node, check],
+    )
+    .expect("Could not add triage");
+}
+
+/// Store every request and count it for each node on its path
+fn add_requests(db: &mut Connection, results: &[OnionResult]) {
+    let res = db.transaction().and_then(|tx| {
+        for res in results {
+            let timestamp = to_timestamp(res.time);
+
+            let ms = timestamp.parse::<u64>().unwrap();
+            let minute = (ms - ms % 60_000).to_string();
+
+            let [guard, relay_1, relay_2] = &res.path;
+
+            tx.execute(
+                "INSERT INTO onion_requests (timestamp, guard, relay_1, relay_2, target, success, error, culprit, kind, version) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![timestamp, guard, relay_1, relay_2, res.target, res.success, res.error, res.culprit, res.kind, res.version.to_string()],
+            )?;
+
+            tx.execute(
+                "INSERT INTO kind_results (minute, kind, total, successful) values (?1, ?2, 1, ?3)
+                ON CONFLICT (minute, kind) DO UPDATE SET
+                    total = total + 1,
+                    successful = successful + excluded.successful",
+                params![minute, res.kind, res.success as u32],
+            )?;
+
+            let roles = [
+                (guard, "guard"),
+                (relay_1, "relay"),
+                (relay_2, "relay"),
+                (&res.target, "target"),
+            ];
+
+            let nodes = if res.server_target { 3 } else { 4 };
+
+            for (node, role) in &roles[..nodes] {
+                tx.execute(
+                    "INSERT INTO node_results (minute, node, role, total, successful) values (?1, ?2, ?3, 1, ?4)
+                    ON CONFLICT (minute, node, role) DO UPDATE SET
+                        total = total + 1,
+                        successful = successful + excluded.successful",
+                    params![minute, node, role, res.success as u32],
+                )?;
+            }
+        }
+
+        tx.commit()
+    });
+
+    if let Err(error) = res {
+        eprintln!("Could not insert: {}", error);
+    }
+}
+
+/// Requests from the last `period`, for `inference::estimate`
+pub(super) fn read_observations(path: &Path, period: Duration) -> Result<Vec<Observation>, String> {
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|err| format!("Could not open {}: {}", path.display(), err))?;
+
+    get_observations(&db, period).map_err(|err| format!("Could not read requests: {}", err))
+}
+
+fn get_observations(db: &Connection, period: Duration) -> rusqlite::Result<Vec<Observation>> {
+    let mut stmt = db.prepare(
+        "SELECT guard, relay_1, relay_2, target, success FROM onion_requests
+        WHERE CAST(timestamp AS INTEGER) >= CAST(?1 AS INTEGER)",
+    )?;
+
+    let since = to_timestamp(SystemTime::now() - period);
+
+    let rows = stmt.query_map(params![since], |row| {
+        Ok(Observation {
+            nodes: vec![row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?],
+            success: row.get(4)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+fn stats_since() -> String {
+    to_timestamp(SystemTime::now() - NODE_STATS_PERIOD)
+}
+
+/// Stats of every node (or just `node`) over `NODE_STATS_PERIOD`, ordered by key
+fn get_node_stats(db: &Connection, node: Option<&str>) -> Vec<NodeStats> {
+    let mut stmt = db
+        .prepare(
+            "SELECT node, role, SUM(total), SUM(successful) FROM node_results
+            WHERE CAST(minute AS INTEGER) >= CAST(?1 AS INTEGER) AND (?2 IS NULL OR node = ?2)
+            GROUP BY node, role ORDER BY node",
+        )
+        .expect("Failed to prepare db statement");
+
+    let rows = stmt
+        .query_map(params![stats_since(), node], |row| {
+            let node: String = row.get(0)?;
+            let role: String = row.get(1)?;
+
+            Ok((node, role, RoleStats::new(row.get(2)?, row.get(3)?)))
+        })
+        .unwrap()
+        .map(|x| x.unwrap());
+
+    let mut nodes: Vec<NodeStats> = vec![];
+
+    for (node, role, stats) in rows {
+        if nodes.last().map(|n| &n.pubkey) != Some(&node) {
+            nodes.push(NodeStats {
+                pubkey: node,
+                guard: Default::default(),
+                relay: Default::default(),
+                target: Default::default(),
+            });
+        }
+
+        let last = nodes.last_mut().unwrap();
+
+        match role.as_str() {
+            "guard" => last.guard = stats,
+            "relay" => last.relay = stats,
+            _ => last.target = stats,
+        }
+    }
+
+    nodes
+}
+
+fn get_node_minutes(db: &Connection, node: &str) -> Vec<NodeMinute> {
+    let mut stmt = db
+        .prepare(
+            "SELECT minute, role, total, successful FROM node_results
+            WHERE CAST(minute AS INTEGER) >= CAST(?1 AS INTEGER) AND node = ?2
+            ORDER BY CAST(minute AS INTEGER)",
+        )
+        .expect("Failed to prepare db statement");
+
+    let results: Vec<_> = stmt
+        .query_map(params![stats_since(), node], |row| {
+            let minute: String = row.get(0)?;
+
+            Ok(NodeMinute {
+                time: from_timestamp(&minute),
+                role: row.get(1)?,
+                total: row.get(2)?,
+                successful: row.get(3)?,
+            })
+        })
+        .unwrap()
+        .map(|x| x.unwrap())
+        .collect();
+
+    results
+}
+
+fn add_delivery(db: &Connection, res: &DeliveryResult) {
+    let latency_ms = res.latency.map(|latency| latency.as_millis() as u32);
+
+    if let Err(error) = db.execute(
+        "INSERT INTO delivery_probes (timestamp, stored, latency_ms) values (?1, ?2, ?3)",
+        params![to_timestamp(res.time), res.stored, latency_ms],
+    ) {
+        eprintln!("Could not insert: {}", error);
+    }
+}
+
+/// Delivery probes over `NODE_STATS_PERIOD` in `DELIVERY_WINDOW`s, oldest first
+fn get_delivery(db: &Connection) -> Vec<DeliveryAggregated> {
+    let window_ms = DELIVERY_WINDOW.as_millis() as u32;
+
+    let mut stmt = db
+        .prepare(
+            "SELECT CAST(timestamp AS INTEGER) / ?2 * ?2 AS window, COUNT(*), SUM(stored),
+                COUNT(latency_ms), AVG(latency_ms)
+            FROM delivery_probes
+            WHERE CAST(timestamp AS INTEGER) >= CAST(?1 AS INTEGER)
+            GROUP BY window ORDER BY window",
+        )
+        .expect("Failed to prepare db statement");
+
+    let results: Vec<_> = stmt
+        .query_map(params![stats_since(), window_ms], |row| {
+            let window: i64 = row.get(0)?;
+            let stored: u32 = row.get(2)?;
+            let delivered: u32 = row.get(3)?;
+
+            Ok(DeliveryAggregated {
+                time: from_timestamp(&window.to_string()),
+                total: row.get(1)?,
+                stored,
+                delivered,
+                loss_rate: Some(1.0 - delivered as f64 / stored as f64).filter(|_| stored > 0),
+                latency_ms: row.get(4)?,
+            })
+        })
+        .unwrap()
+        .map(|x| x.unwrap())
+        .collect();
+
+    results
+}
+
+/// Results of every request kind per minute over `NODE_STATS_PERIOD`
+fn get_kind_minutes(db: &Connection) -> Vec<KindMinute> {
+    let mut stmt = db
+        .prepare(
+            "SELECT minute, kind, total, successful FROM kind_results
+            WHERE CAST(minute AS INTEGER) >= CAST(?1 AS INTEGER)
+            ORDER BY CAST(minute AS INTEGER), kind",
+        )
+        .expect("Failed to prepare db statement");
+
+    let results: Vec<_> = stmt
+        .query_map(params![stats_since()], |row| {
+            let minute: String = row.get(0)?;
+
+            Ok(KindMinute {
+                time: from_timestamp(&minute),
+                kind: row.get(1)?,
+                total: row.get(2)?,
+                successful: row.get(3)?,
+            })
+        })
+        .unwrap()
+        .map(|x| x.unwrap())
+        .collect();
+
+    results
+}
+
+/// What failed paths over `NODE_STATS_PERIOD` were blamed on, most common first
+fn get_broken_hops(db: &Connection) -> Vec<BrokenHop> {
+    let mut stmt = db
+        .prepare(
+            "SELECT verdict, node, next_node, check_name, COUNT(*) FROM path_triage
+            WHERE CAST(timestamp AS INTEGER) >= CAST(?1 AS INTEGER)
+            GROUP BY verdict, node, next_node, check_name
+            ORDER BY COUNT(*) DESC",
+        )
+        .expect("Failed to prepare db statement");
+
+    let results: Vec<_> = stmt
+        .query_map(params![stats_since()], |row| {
+            Ok(BrokenHop {
+                verdict: row.get(0)?,
+                node: row.get(1)?,
+                next_node: row.get(2)?,
+                check: row.get(3)?,
+                count: row.get(4)?,
+            })
+        })
+        .unwrap()
+        .map(|x| x.unwrap())
+        .collect();
+
+    results
+}
+
+/// `before` and `after` hold what changed: the address, or the ed25519/x25519 keys
+fn add_churn(db: &mut Connection, time: SystemTime, total: usize, diff: &PoolDiff) {
+    let timestamp = to_timestamp(time);
+
+    let keys = |n: &crate::loki::ServiceNode| format!("{}/{}", n.pubkey_ed25519, n.pubkey_x25519);
+
+    let mut changes: Vec<(&str, &str, Option<String>, Option<String>)> = vec![];
+
+    for n in &diff.joined {
+        changes.push((node_id(n), "joined", None, Some(n.to_string())));
+    }
+
+    for n in &diff.left {
+        changes.push((node_id(n), "left", Some(n.to_string()), None));
+    }
+
+    for (old, new) in &diff.changed_ip {
+        changes.push((
+            node_id(new),
+            "changed_ip",
+            Some(old.to_string()),
+            Some(new.to_string()),
+        ));
+    }
+
+    for (old, new) in &diff.changed_keys {
+        changes.push((
+            node_id(new),
+            "changed_keys",
+            Some(keys(old)),
+            Some(keys(new)),
+        ));
+    }
+
+    let res = db.transaction().and_then(|tx| {
+        tx.execute(
+            "INSERT INTO pool_refreshes (timestamp, total) values (?1, ?2)",
+            params![timestamp, total as u32],
+        )?;
+
+        for (node, change, before, after) in changes {
+            tx.execute(
+                "INSERT INTO node_churn (timestamp, node, change, before, after) values (?1, ?2, ?3, ?4, ?5)",
+                params![timestamp, node, change, before, after],
+            )?;
+        }
+
+        tx.commit()
+    });
+
+    if let Err(error) = res {
+        eprintln!("Could not insert: {}", error);
+    }
+}
+
+/// Latest refreshes, oldest first
+fn get_churn(db: &Connection) -> Vec<PoolChurn> {
+    let mut stmt = db
+        .prepare(
+            "SELECT * FROM (
+                SELECT r.timestamp, r.total,
+                    COUNT(CASE WHEN c.change = 'joined' THEN 1 END),
+                    COUNT(CASE WHEN c.change = 'left' THEN 1 END),
+                    COUNT(CASE WHEN c.change = 'changed_ip' THEN 1 END),
+                    COUNT(CASE WHEN c.change = 'changed_keys' THEN 1 END)
+                FROM pool_refreshes r LEFT JOIN node_churn c ON c.timestamp = r.timestamp
+                GROUP BY r.timestamp
+                ORDER BY CAST(r.timestamp AS INTEGER) DESC
+                LIMIT 720
+            ) ORDER BY CAST(timestamp AS INTEGER)",
+        )
+        .expect("Failed to prepare db statement");
+
+    let results: Vec<_> = stmt
+        .query_map(params![], |row| {
+            let timestamp: String = row.get(0)?;
+
+            Ok(PoolChurn {
+                time: from_timestamp(&timestamp),
+                total: row.get(1)?,
+                joined: row.get(2)?,
+                left: row.get(3)?,
+                changed_ip: row.get(4)?,
+                changed_keys: row.get(5)?,
+            })
+        })
+        .unwrap()
+        .map(|x| x.unwrap())
+        .collect();
+
+    results
 }
 
 pub(super) fn add_entry(db: &Connection, res: OnionResultAggregated) {
@@ -97,3 +643,241 @@ fn get_entries(db: &Connection) -> Vec<OnionResultAggregated> {
 
     results
 }
+
+#[test]
+fn test_churn() {
+    use crate::node_pool::test_nodes;
+
+    let mut db = Connection::open_in_memory().unwrap();
+
+    create_tables(&db);
+
+    let nodes = test_nodes(4);
+
+    let mut changed = nodes[1..].to_vec();
+    changed[0].public_ip = "10.0.0.1".to_owned();
+
+    let start = SystemTime::now();
+
+    add_churn(&mut db, start, 4, &PoolDiff::new(&[], &nodes));
+    add_churn(
+        &mut db,
+        start + Duration::from_secs(600),
+        3,
+        &PoolDiff::new(&nodes, &changed),
+    );
+    add_churn(
+        &mut db,
+        start + Duration::from_secs(1200),
+        3,
+        &PoolDiff::new(&changed, &changed),
+    );
+
+    let churn = get_churn(&db);
+
+    let counts: Vec<_> = churn
+        .iter()
+        .map(|c| (c.total, c.joined, c.left, c.changed_ip, c.changed_keys))
+        .collect();
+
+    assert_eq!(
+        counts,
+        vec![(4, 4, 0, 0, 0), (3, 0, 1, 1, 0), (3, 0, 0, 0, 0)]
+    );
+}
+
+#[test]
+fn test_node_stats() {
+    use crate::onions_core::OnionVersion;
+
+    let mut db = Connection::open_in_memory().unwrap();
+
+    create_tables(&db);
+
+    let now = SystemTime::now();
+
+    let result = |path: [&str; 4], success| OnionResult {
+        time: now,
+        success,
+        path: [path[0].to_owned(), path[1].to_owned(), path[2].to_owned()],
+        target: path[3].to_owned(),
+        server_target: path[3].contains('.'),
+        kind: if path[3] == "a" {
+            "retrieve"
+        } else {
+            "get_snodes"
+        }
+        .to_owned(),
+        version: OnionVersion::V2,
+        error: Some("guard_status").filter(|_| !success),
+        culprit: None,
+    };
+
+    add_requests(
+        &mut db,
+        &[
+            result(["a", "b", "c", "d"], true),
+            result(["a", "c", "b", "e"], false),
+            result(["b", "c", "d", "a"], true),
+            result(["d", "b", "e", "file.server"], true),
+        ],
+    );
+
+    let stats = get_node_stats(&db, None);
+
+    // Servers aren't nodes
+    assert_eq!(stats.len(), 5);
+
+    let a = &stats[0];
+    assert_eq!(a.pubkey, "a");
+    assert_eq!((a.guard.total, a.guard.successful), (2, 1));
+    assert_eq!(a.guard.success_rate, Some(0.5));
+    assert_eq!((a.target.total, a.target.successful), (1, 1));
+    assert_eq!(a.relay.total, 0);
+
+    let c = get_node_stats(&db, Some("c")).pop().unwrap();
+    assert_eq!((c.relay.total, c.relay.successful), (3, 2));
+
+    // All requests were in the same minute
+    let minutes = get_node_minutes(&db, "c");
+    assert_eq!(minutes.len(), 1);
+    assert_eq!(minutes[0].total, 3);
+
+    assert!(get_broken_hops(&db).is_empty());
+
+    let kinds: Vec<_> = get_kind_minutes(&db)
+        .into_iter()
+        .map(|k| (k.kind, k.total, k.successful))
+        .collect();
+
+    assert_eq!(
+        kinds,
+        [
+            ("get_snodes".to_owned(), 3, 2),
+            ("retrieve".to_owned(), 1, 1)
+        ]
+    );
+
+    let observations = get_observations(&db, Duration::from_secs(60)).unwrap();
+    assert_eq!(observations.len(), 4);
+    assert_eq!(observations[1].nodes, ["a", "c", "b", "e"]);
+    assert!(!observations[1].success);
+}
+
+#[test]
+fn test_broken_hops() {
+    use crate::triage::Check;
+
+    let db = Connection::open_in_memory().unwrap();
+
+    create_tables(&db);
+
+    let link = Verdict::Link {
+        from: "b".to_owned(),
+        to: "c".to_owned(),
+    };
+
+    let node = Verdict::Node {
+        pubkey: "d".to_owned(),
+        check: Check::SelfOnion,
+    };
+
+    for verdict in &[link.clone(), node, link] {
+        add_triage(&db, SystemTime::now(), verdict);
+    }
+
+    let broken = get_broken_hops(&db);
+
+    assert_eq!(broken.len(), 2);
+    assert_eq!(broken[0].verdict, "link");
+    assert_eq!(broken[0].node.as_deref(), Some("b"));
+    assert_eq!(broken[0].next_node.as_deref(), Some("c"));
+    assert_eq!(broken[0].count, 2);
+    assert_eq!(broken[1].check.as_deref(), Some("self_onion"));
+}
+
+#[test]
+fn test_delivery() {
+    let db = Connection::open_in_memory().unwrap();
+
+    create_tables(&db);
+
+    let window_start = {
+        let now = to_timestamp(SystemTime::now()).parse::<u64>().unwrap();
+        let window = DELIVERY_WINDOW.as_millis() as u64;
+        from_timestamp(&(now - now % window).to_string())
+    };
+
+    let probe = |stored, latency_ms: Option<u64>| DeliveryResult {
+        time: window_start,
+        stored,
+        latency: latency_ms.map(Duration::from_millis),
+    };
+
+    add_delivery(&db, &probe(true, Some(100)));
+    add_delivery(&db, &probe(true, Some(300)));
+    add_delivery(&db, &probe(true, None));
+    add_delivery(&db, &probe(false, None));
+
+    let delivery = get_delivery(&db);
+
+    assert_eq!(delivery.len(), 1);
+
+    let window = &delivery[0];
+
+    assert_eq!(window.time, window_start);
+    assert_eq!((window.total, window.stored, window.delivered), (4, 3, 2));
+    assert_eq!(window.latency_ms, Some(200.0));
+    assert!((window.loss_rate.unwrap() - 1.0 / 3.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_prune() {
+    use crate::onions_core::OnionVersion;
+
+    let mut db = Connection::open_in_memory().unwrap();
+
+    create_tables(&db);
+
+    let now = SystemTime::now();
+    let old = now - RETENTION - Duration::from_secs(60);
+
+    let result = |time| OnionResult {
+        time,
+        success: true,
+        path: ["a".to_owned(), "b".to_owned(), "c".to_owned()],
+        target: "d".to_owned(),
+        server_target: false,
+        kind: "get_snodes".to_owned(),
+        version: OnionVersion::V4,
+        error: None,
+        culprit: None,
+    };
+
+    add_requests(&mut db, &[result(old), result(now)]);
+    add_triage(&db, old, &Verdict::Inconclusive);
+
+    prune(&db, now - RETENTION);
+
+    let count = |table: &str| -> u32 {
+        db.query_row(
+            &format!("SELECT COUNT(*) FROM {}", table),
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .unwrap()
+    };
+
+    assert_eq!(count("onion_requests"), 1);
+
+    let version: String = db
+        .query_row("SELECT version FROM onion_requests", NO_PARAMS, |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(version, "v4");
+    assert_eq!(count("path_triage"), 0);
+    // One row per node on the path, for the recent minute only
+    assert_eq!(count("node_results"), 4);
+    assert_eq!(count("kind_results"), 1);
+}
@@ -0,0 +1,174 @@
+use std::{
+    convert::TryInto,
+    time::{Duration, Instant, SystemTime},
+};
+
+use rand::{prelude::SliceRandom, thread_rng, RngCore};
+
+use crate::{
+    loki::{self, Network, ServiceNode},
+    onions::{send_onion_req, NextHop},
+    onions_core::OnionVersion,
+    sn_api,
+};
+
+use super::DeliveryResult;
+
+/// A message that isn't visible after this long is lost
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Ask at most this many swarm members in every poll
+const POLL_NODES: usize = 5;
+
+const MESSAGE_TTL_MS: u64 = 10 * 60 * 1000;
+
+/// Store a tagged message for a new key on a random node, then poll the rest of
+/// the key's swarm (through new paths, in onion format `version`) until the message shows up
+pub(super) async fn delivery_probe(
+    nodes: &[ServiceNode],
+    net: &Network,
+    version: OnionVersion,
+) -> DeliveryResult {
+    let (target, pk, tag, path) = {
+        let mut rng = thread_rng();
+
+        // A lone swarm member has no one to pass the message to
+        let candidates: Vec<&ServiceNode> = nodes
+            .iter()
+            .filter(|n| nodes.iter().filter(|m| m.swarm_id == n.swarm_id).count() > 1)
+            .collect();
+
+        let target = (*candidates
+            .choose(&mut rng)
+            .expect("No swarm with several nodes"))
+        .clone();
+
+        let pk = loki::PubKey::gen_in_swarm(&mut rng, target.swarm_id, net).to_string();
+
+        let mut tag = [0u8; 16];
+        rng.fill_bytes(&mut tag);
+
+        (target, pk, tag.to_vec(), random_path(nodes))
+    };
+
+    let time = SystemTime::now();
+
+    let payload = sn_api::store_request(&pk, &base64::encode(&tag), MESSAGE_TTL_MS).to_string();
+
+    let stored = send_onion_req(
+        path,
+        NextHop::Node(target.clone()),
+        payload.as_bytes(),
+        0,
+        version,
+        Default::default(),
+    )
+    .await;
+
+    if let Err(err) = stored {
+        eprintln!("Delivery probe could not store: {}", err);
+
+        return DeliveryResult {
+            time,
+            stored: false,
+            latency: None,
+        };
+    }
+
+    let stored_at = Instant::now();
+
+    // The target has the message as soon as it accepts it, so only the rest
+    // of the swarm tells how long the message takes to propagate
+    let swarm: Vec<ServiceNode> = nodes
+        .iter()
+        .filter(|n| n.swarm_id == target.swarm_id && n.pubkey_ed25519 != target.pubkey_ed25519)
+        .cloned()
+        .collect();
+
+    let latency = loop {
+        if stored_at.elapsed() > DELIVERY_TIMEOUT {
+            break None;
+        }
+
+        let polled: Vec<ServiceNode> = swarm
+            .choose_multiple(&mut thread_rng(), POLL_NODES)
+            .cloned()
+            .collect();
+
+        let polls = polled
+            .into_iter()
+            .map(|node| has_message(node, &pk, &tag, nodes, version));
+
+        if futures::future::join_all(polls).await.contains(&true) {
+            break Some(stored_at.elapsed());
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    };
+
+    DeliveryResult {
+        time,
+        stored: true,
+        latency,
+    }
+}
+
+fn random_path(nodes: &[ServiceNode]) -> [ServiceNode; 3] {
+    let path: Vec<ServiceNode> = nodes
+        .choose_multiple(&mut thread_rng(), 3)
+        .cloned()
+        .collect();
+
+    path.try_into().expect("Not enough nodes for a path")
+}
+
+/// Whether `node` returns the message with `tag` for `pk`
+async fn has_message(
+    node: ServiceNode,
+    pk: &str,
+    tag: &[u8],
+    nodes: &[ServiceNode],
+    version: OnionVersion,
+) -> bool {
+    let payload = sn_api::retrieve_request(pk, "").to_string();
+
+    let res = send_onion_req(
+        random_path(nodes),
+        NextHop::Node(node),
+        payload.as_bytes(),
+        0,
+        version,
+        Default::default(),
+    )
+    .await;
+
+    let res: serde_json::Value = match res.map(|res| serde_json::from_str(&res)) {
+        Ok(Ok(res)) => res,
+        _ => return false,
+    };
+
+    res["messages"].as_array().is_some_and(|messages| {
+        messages.iter().any(|m| {
+            m["data"]
+                .as_str()
+                .and_then(|data| base64::decode(data).ok())
+                .is_some_and(|data| data == tag)
+        })
+    })
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_delivery_probe() {
+    use crate::{fake_net::FakeNetwork, loki::LOCAL_NET};
+
+    let net = FakeNetwork::start(2, 3);
+
+    for version in [OnionVersion::V2, OnionVersion::V4] {
+        let res = delivery_probe(&net.service_nodes(), &LOCAL_NET, version).await;
+
+        assert!(res.stored);
+        assert!(res.latency.unwrap() < DELIVERY_TIMEOUT);
+    }
+}
@@ -1,18 +1,27 @@
 use std::{
     convert::TryInto,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, SystemTime},
 };
 
 use parking_lot::RwLock;
 use rand::{
-    prelude::{SliceRandom, ThreadRng},
+    prelude::SliceRandom,
     thread_rng, RngCore,
 };
 
 use crate::{
+    inference,
     loki::{self, Network, ServiceNode},
-    onions::{send_onion_req, NextHop},
+    node_pool,
+    onions::{send_onion_req, NextHop, OnionError, OnionErrorKind},
+    onions_core::OnionVersion,
+    pool_snapshot::{PoolDiff, PoolSnapshot},
+    triage,
     ServeOptions,
 };
 
@@ -25,15 +34,33 @@ use rouille::router;
 use serde::Serialize;
 
 mod database;
+mod delivery;
+mod workload;
 
 use database as db;
 
+pub use workload::Workload;
+
 use self::database::ResultsDb;
 
 #[derive(Debug)]
 struct OnionResult {
     time: std::time::SystemTime,
     success: bool,
+    /// Ed25519 keys of the guard and the two relays
+    path: [String; 3],
+    /// Ed25519 key of the target node, or the host of the target server
+    target: String,
+    /// The target is a server, which has no place in per-node results
+    server_target: bool,
+    /// `RequestKind::tag`
+    kind: String,
+    version: OnionVersion,
+    /// `OnionErrorKind::name` for requests that got an error, including
+    /// target statuses that still count as success
+    error: Option<&'static str>,
+    /// Ed25519 key of the node the error points at, if any
+    culprit: Option<String>,
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -43,6 +70,102 @@ struct OnionResultAggregated {
     total_success: u32,
 }
 
+/// Requests through a node in one role
+#[derive(Debug, Clone, Default, serde::Serialize)]
+struct RoleStats {
+    total: u32,
+    successful: u32,
+    /// Not set without any requests
+    success_rate: Option<f64>,
+}
+
+impl RoleStats {
+    fn new(total: u32, successful: u32) -> Self {
+        RoleStats {
+            total,
+            successful,
+            success_rate: Some(successful as f64 / total as f64).filter(|_| total > 0),
+        }
+    }
+}
+
+/// How requests went through a node as guard, relay (node 2 or 3) and target
+#[derive(Debug, Clone, serde::Serialize)]
+struct NodeStats {
+    pubkey: String,
+    guard: RoleStats,
+    relay: RoleStats,
+    target: RoleStats,
+}
+
+/// Requests through a node in one role during one minute
+#[derive(Debug, Clone, serde::Serialize)]
+struct NodeMinute {
+    time: std::time::SystemTime,
+    role: String,
+    total: u32,
+    successful: u32,
+}
+
+/// One message stored and polled for by the delivery probe
+#[derive(Debug)]
+struct DeliveryResult {
+    /// When the message was sent
+    time: std::time::SystemTime,
+    /// Whether the node took the message
+    stored: bool,
+    /// How long until the message was visible, not set if it never was
+    latency: Option<Duration>,
+}
+
+/// Delivery probes over `DELIVERY_WINDOW`
+#[derive(Debug, Clone, serde::Serialize)]
+struct DeliveryAggregated {
+    time: std::time::SystemTime,
+    total: u32,
+    stored: u32,
+    delivered: u32,
+    /// Of the stored messages that were not delivered
+    loss_rate: Option<f64>,
+    /// Average over the delivered messages
+    latency_ms: Option<f64>,
+}
+
+/// Requests of one kind during one minute
+#[derive(Debug, Clone, serde::Serialize)]
+struct KindMinute {
+    time: std::time::SystemTime,
+    kind: String,
+    total: u32,
+    successful: u32,
+}
+
+/// How many failed paths the probes blamed on one node (or link)
+#[derive(Debug, Clone, serde::Serialize)]
+struct BrokenHop {
+    /// `Verdict::kind`
+    verdict: String,
+    /// Ed25519 key of the node, or the first node of the link
+    node: Option<String>,
+    /// Ed25519 key of the second node of the link
+    next_node: Option<String>,
+    /// `Check::name` of the check the node failed
+    check: Option<String>,
+    count: u32,
+}
+
+/// What changed in the node pool on one refresh
+#[derive(Debug, Clone, serde::Serialize)]
+struct PoolChurn {
+    time: std::time::SystemTime,
+    /// Pool size after the refresh
+    total: u32,
+    joined: u32,
+    left: u32,
+    changed_ip: u32,
+    changed_keys: u32,
+}
+
 const BUFFER_LIMIT: usize = 720;
 
 #[derive(Debug)]
@@ -104,6 +227,9 @@ impl OnionResults {
 
         // println!("Total success: {}/{}", total_success, results.len());
 
+        self.db.add_requests(results);
+        self.db.prune();
+
         results.clear();
 
         if self.results_new.len() == BUFFER_LIMIT {
@@ -122,14 +248,27 @@ impl OnionResults {
 struct Context {
     net: Network,
     node_pool: Vec<ServiceNode>,
+    /// Refresh the pool from this many of its nodes rather than from the seed
+    refresh_sources: Option<usize>,
+    workload: Workload,
+    /// Every request uses one of these, picked at random
+    onion_versions: Vec<OnionVersion>,
     onion_results: OnionResults,
 }
 
 impl Context {
-    pub fn new(net: Network) -> Self {
+    pub fn new(
+        net: Network,
+        refresh_sources: Option<usize>,
+        workload: Workload,
+        onion_versions: Vec<OnionVersion>,
+    ) -> Self {
         Context {
             node_pool: vec![],
             net,
+            refresh_sources,
+            workload,
+            onion_versions,
             onion_results: OnionResults::new(),
         }
     }
@@ -141,10 +280,17 @@ pub async fn start(net: Network, options: ServeOptions) {
         std::process::exit(101); // Rust's panics use 101 by default
     }));
 
-    let ctx = Context::new(net);
+    let ctx = Context::new(
+        net,
+        options.refresh_sources,
+        options.workload.clone(),
+        options.onion_versions.clone(),
+    );
 
     let ctx = Arc::new(RwLock::new(ctx));
 
+    let delivery_probes = !options.no_delivery_probes;
+
     // TODO: Start the http server
 
     let ctx_clone = ctx.clone();
@@ -155,7 +301,7 @@ pub async fn start(net: Network, options: ServeOptions) {
 
     // TODO: Initiate periodic testing of nodes
 
-    let fut2 = start_testing(ctx);
+    let fut2 = start_testing(ctx, delivery_probes);
 
     join!(fut2);
 }
@@ -185,6 +331,62 @@ fn serve_http(ctx: Arc<RwLock<Context>>, options: ServeOptions) {
 
                 res.with_additional_header("Access-Control-Allow-Origin", "*")
             },
+
+            (GET) (/nodes) => {
+
+                let nodes = ctx.read().onion_results.db.read_node_stats();
+
+                rouille::Response::json(&nodes)
+                    .with_additional_header("Access-Control-Allow-Origin", "*")
+            },
+
+            (GET) (/nodes/{pubkey: String}) => {
+
+                let db = &ctx.read().onion_results.db;
+
+                let node = db.read_node_stats_for(&pubkey);
+                let minutes = db.read_node_minutes(&pubkey);
+
+                let res = serde_json::json!({
+                    "node": node,
+                    "minutes": minutes,
+                });
+
+                rouille::Response::json(&res)
+                    .with_additional_header("Access-Control-Allow-Origin", "*")
+            },
+
+            (GET) (/delivery) => {
+
+                let delivery = ctx.read().onion_results.db.read_delivery();
+
+                rouille::Response::json(&delivery)
+                    .with_additional_header("Access-Control-Allow-Origin", "*")
+            },
+
+            (GET) (/kinds) => {
+
+                let kinds = ctx.read().onion_results.db.read_kind_minutes();
+
+                rouille::Response::json(&kinds)
+                    .with_additional_header("Access-Control-Allow-Origin", "*")
+            },
+
+            (GET) (/triage) => {
+
+                let broken = ctx.read().onion_results.db.read_broken_hops();
+
+                rouille::Response::json(&broken)
+                    .with_additional_header("Access-Control-Allow-Origin", "*")
+            },
+
+            (GET) (/churn) => {
+
+                let churn = ctx.read().onion_results.db.read_churn();
+
+                rouille::Response::json(&churn)
+                    .with_additional_header("Access-Control-Allow-Origin", "*")
+            },
             _ => {
                 let response = rouille::match_assets(&req, "./html");
 
@@ -198,17 +400,69 @@ fn serve_http(ctx: Arc<RwLock<Context>>, options: ServeOptions) {
     });
 }
 
+/// Rank nodes by how likely they are to break requests, from the requests
+/// the server made in the last `period`
+pub fn print_bad_nodes(db_path: &Path, period: Duration, top: usize) {
+    let observations = match db::read_observations(db_path, period) {
+        Ok(observations) => observations,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+
+    println!("Requests: {}", observations.len());
+
+    inference::print_estimates(&inference::estimate(&observations), top);
+}
+
 async fn periodically_refresh_node_pool(ctx: Arc<RwLock<Context>>) {
     const PERIOD: Duration = Duration::from_secs(600);
 
     let net = ctx.read().net.clone();
 
+    let snapshot_path = net.snapshot_file.as_ref().map(|path| PathBuf::from(path.as_ref()));
+
+    // Start with the nodes from the last run, in case the seed can't be reached
+    if let Some(path) = &snapshot_path {
+        match PoolSnapshot::load(path) {
+            Ok(snapshot) => {
+                println!("Loaded {} nodes from {}", snapshot.nodes.len(), path.display());
+                ctx.write().node_pool = snapshot.nodes;
+            }
+            Err(err) => eprintln!("No pool snapshot: {}", err),
+        }
+    }
+
     loop {
-        match loki::get_n_service_nodes(0, &net).await {
-            Ok(mut nodes) => {
-                println!("Updated nodes, count: {}", nodes.len());
+        let sources: Vec<ServiceNode> = {
+            let ctx = ctx.read();
+            let n = ctx.refresh_sources.unwrap_or(0);
+
+            ctx.node_pool
+                .choose_multiple(&mut thread_rng(), n)
+                .cloned()
+                .collect()
+        };
 
-                let prev = nodes.len();
+        // The seed is still needed for the first pool, and whenever nodes let us down
+        let from_nodes = if sources.is_empty() {
+            None
+        } else {
+            node_pool::fetch_from_nodes(&sources, &net)
+                .await
+                .map_err(|err| eprintln!("Failed to update nodes from the pool: {}", err))
+                .ok()
+        };
+
+        let res = match from_nodes {
+            Some(nodes) => Ok(nodes),
+            None => loki::get_n_service_nodes(0, &net).await,
+        };
+
+        match res {
+            Ok(nodes) => {
+                println!("Updated nodes, count: {}", nodes.len());
 
                 for n in &nodes {
                     if n.pubkey_x25519.len() != 64 {
@@ -216,16 +470,30 @@ async fn periodically_refresh_node_pool(ctx: Arc<RwLock<Context>>) {
                     }
                 }
 
-                // Known bad node on testnet
-                nodes.retain(|n| n.pubkey_ed25519 != "decaf01cea9acab5d457a7896d1104752b413f7de864322368820b36ea3abfff");
+                let prev = std::mem::replace(&mut ctx.write().node_pool, nodes.clone());
 
-                if prev-nodes.len() > 0 {
-                    println!("Removed nodes: {}", prev - nodes.len());
+                // Every node is new on the first refresh, that isn't worth listing
+                if !prev.is_empty() {
+                    let diff = PoolDiff::new(&prev, &nodes);
+
+                    diff.print();
+
+                    ctx.read()
+                        .onion_results
+                        .db
+                        .add_churn(SystemTime::now(), nodes.len(), &diff);
                 }
 
-                ctx.write().node_pool = nodes;
+                if let Some(path) = &snapshot_path {
+                    // The CLI keeps node scores in the same file, those aren't ours to reset
+                    let reputation = PoolSnapshot::load(path)
+                        .map(|snapshot| snapshot.reputation)
+                        .unwrap_or_default();
 
-                
+                    if let Err(err) = PoolSnapshot::new(nodes, reputation).save(path) {
+                        eprintln!("{}", err);
+                    }
+                }
             }
             Err(err) => {
                 eprintln!("Failed to update nodes from seed: {}", err);
@@ -236,20 +504,21 @@ async fn periodically_refresh_node_pool(ctx: Arc<RwLock<Context>>) {
     }
 }
 
-fn test_payload(rng: &mut ThreadRng, network: &Network) -> String {
-    let pk = loki::PubKey::gen_random(rng, &network);
-
-    let params = serde_json::json!({
-        "method": "get_snodes_for_pubkey",
-        "params": {
-            "pubKey": &pk.to_string(),
-        }
-    });
-
-    params.to_string()
+fn choose_version(ctx: &Context) -> OnionVersion {
+    *ctx.onion_versions
+        .choose(&mut thread_rng())
+        .unwrap_or(&OnionVersion::V2)
 }
 
-async fn onion_req_task(ctx: Arc<RwLock<Context>>) -> bool {
+/// The result, and the error for failed requests
+async fn onion_req_task(ctx: Arc<RwLock<Context>>) -> (OnionResult, Option<OnionError>) {
+    let (net, kind, version) = {
+        let ctx = ctx.read();
+        let kind = ctx.workload.choose(&mut thread_rng()).clone();
+        let version = choose_version(&ctx);
+        (ctx.net.clone(), kind, version)
+    };
+
     let mut nodes: Vec<_> = {
         let mut rng = rand::thread_rng();
 
@@ -260,45 +529,134 @@ async fn onion_req_task(ctx: Arc<RwLock<Context>>) -> bool {
             .collect()
     };
 
-    let target: ServiceNode = nodes.pop().expect("Node should exist");
+    // Only node targets are picked from the last node
+    let target_candidates = nodes.split_off(3);
 
     let path: [_; 3] = nodes.try_into().unwrap();
 
+    // Proof of work for large messages takes a while
+    let (target, payload) = {
+        let kind = kind.clone();
+        let net = net.clone();
+
+        tokio::task::spawn_blocking(move || {
+            workload::build_request(&kind, &target_candidates, &net, &mut thread_rng())
+        })
+        .await
+        .expect("Building a request panicked")
+    };
+
     trace!(
-        "Testing [{} -> {} -> {}] -> {}",
+        "Testing [{} -> {} -> {}] -> {} ({}, {})",
         &path[0],
         &path[1],
         &path[2],
-        &target
+        &target,
+        kind.tag(),
+        version
     );
 
-    let target = NextHop::Node(target);
+    let path_keys = [
+        path[0].pubkey_ed25519.clone(),
+        path[1].pubkey_ed25519.clone(),
+        path[2].pubkey_ed25519.clone(),
+    ];
 
-    let payload = {
-        let mut rng = rand::thread_rng();
+    let target_key = match &target {
+        NextHop::Node(n) => n.pubkey_ed25519.clone(),
+        NextHop::Server(server) => server.host.clone(),
+        NextHop::ServerV2(server) => server.host.clone(),
+    };
+
+    let server_target = !matches!(target, NextHop::Node(_));
+
+    let res = send_onion_req(
+        path,
+        target,
+        payload.as_bytes(),
+        0,
+        version,
+        Default::default(),
+    )
+    .await;
+
+    let time = SystemTime::now();
 
-        test_payload(&mut rng, &ctx.read().net)
+    let culprit = res.as_ref().err().and_then(|err| match err.culprit() {
+        Some(NextHop::Node(n)) => Some(n.pubkey_ed25519.clone()),
+        _ => None,
+    });
+
+    // A target answering with an error status (e.g. 421 for the wrong swarm) still got
+    // the request through the path, which is what success measures. The status is
+    // kept in `error`.
+    let success = match &res {
+        Ok(_) => true,
+        Err(err) => matches!(err.kind, OnionErrorKind::Target { .. }),
     };
 
-    let res = send_onion_req(path, target, payload.as_bytes(), 0).await;
+    let result = OnionResult {
+        time,
+        success,
+        path: path_keys,
+        target: target_key,
+        server_target,
+        kind: kind.tag(),
+        version,
+        error: res.as_ref().err().map(|err| err.kind.name()),
+        culprit,
+    };
 
-    if let Err(err) = &res {
-        eprintln!("Error: {}", &err.message);
-    }
-    res.is_ok()
+    (result, res.err())
 }
 
-async fn run_onion_req_task(ctx: Arc<RwLock<Context>>, in_flight: Arc<Mutex<u32>>) {
-    let success = onion_req_task(ctx.clone()).await;
+/// How many failed paths can be probed at once. Probes of a failing path go to the
+/// same nodes that are failing, so during an outage most failures aren't probed.
+const MAX_TRIAGE_IN_FLIGHT: u32 = 3;
+
+async fn run_onion_req_task(
+    ctx: Arc<RwLock<Context>>,
+    in_flight: Arc<Mutex<u32>>,
+    triage_in_flight: Arc<Mutex<u32>>,
+) {
+    let (res, err) = onion_req_task(ctx.clone()).await;
 
     *in_flight.lock().unwrap() -= 1;
 
-    let res = OnionResult {
-        success,
-        time: SystemTime::now(),
-    };
+    let (time, success) = (res.time, res.success);
 
     ctx.write().onion_results.push(res);
+
+    // Probing takes a while, it shouldn't hold up new requests. There is
+    // nothing to probe if the path worked and only the target complained.
+    if let Some(err) = err.filter(|_| !success) {
+        {
+            let mut triage_in_flight = triage_in_flight.lock().unwrap();
+
+            if *triage_in_flight >= MAX_TRIAGE_IN_FLIGHT {
+                trace!("Too many paths already being probed, not probing this one");
+                return;
+            }
+
+            *triage_in_flight += 1;
+        }
+
+        tokio::spawn(async move {
+            triage_task(ctx, time, err).await;
+
+            *triage_in_flight.lock().unwrap() -= 1;
+        });
+    }
+}
+
+async fn triage_task(ctx: Arc<RwLock<Context>>, time: SystemTime, err: OnionError) {
+    let net = ctx.read().net.clone();
+
+    let verdict = triage::triage(&err.path, &net).await.verdict();
+
+    eprintln!("Error: {}, probes: {}", &err, verdict);
+
+    ctx.read().onion_results.db.add_triage(time, &verdict);
 }
 
 async fn sleep_ms(n: u64) {
@@ -310,6 +668,7 @@ async fn onion_request_testing(ctx: Arc<RwLock<Context>>) {
     const MAX_IN_FLIGHT: u32 = 10;
 
     let in_flight = Arc::new(Mutex::new(0u32));
+    let triage_in_flight = Arc::new(Mutex::new(0u32));
 
     loop {
         if ctx.read().node_pool.len() == 0 {
@@ -328,8 +687,47 @@ async fn onion_request_testing(ctx: Arc<RwLock<Context>>) {
 
         let ctx_clone = ctx.clone();
         let in_flight_clone = in_flight.clone();
+        let triage_clone = triage_in_flight.clone();
 
-        tokio::spawn(async move { run_onion_req_task(ctx_clone, in_flight_clone).await });
+        tokio::spawn(
+            async move { run_onion_req_task(ctx_clone, in_flight_clone, triage_clone).await },
+        );
+    }
+}
+
+/// Start a store -> retrieve round trip every `PERIOD`, as delivery takes a while.
+/// A probe can take longer than that, so a new one only starts once the last is done.
+async fn delivery_testing(ctx: Arc<RwLock<Context>>) {
+    const PERIOD: Duration = Duration::from_secs(10);
+
+    let running = Arc::new(AtomicBool::new(false));
+
+    loop {
+        let (nodes, net, version) = {
+            let ctx = ctx.read();
+            (ctx.node_pool.clone(), ctx.net.clone(), choose_version(&ctx))
+        };
+
+        if nodes.len() < 4 {
+            info!("Not enough nodes for the delivery probe, skipping this iteration");
+        } else if running.swap(true, Ordering::Relaxed) {
+            trace!("Delivery probe still running, skipping this iteration");
+        } else {
+            let ctx = ctx.clone();
+            let running = running.clone();
+
+            tokio::spawn(async move {
+                let res = delivery::delivery_probe(&nodes, &net, version).await;
+
+                trace!("Delivery probe: {:?}", res);
+
+                ctx.read().onion_results.db.add_delivery(&res);
+
+                running.store(false, Ordering::Relaxed);
+            });
+        }
+
+        async_std::task::sleep(PERIOD).await;
     }
 }
 
@@ -342,14 +740,18 @@ async fn aggregate_results(ctx: Arc<RwLock<Context>>) {
     }
 }
 
-async fn start_testing(ctx: Arc<RwLock<Context>>) {
+async fn start_testing(ctx: Arc<RwLock<Context>>, delivery_probes: bool) {
     let fut = periodically_refresh_node_pool(ctx.clone());
 
     let fut2 = onion_request_testing(ctx.clone());
 
-    let fut3 = aggregate_results(ctx);
+    let fut3 = aggregate_results(ctx.clone());
 
-    join!(fut, fut2, fut3);
+    if delivery_probes {
+        join!(fut, fut2, fut3, delivery_testing(ctx));
+    } else {
+        join!(fut, fut2, fut3);
+    }
 
     // periodically update node pool
 }
@@ -0,0 +1,244 @@
+use std::str::FromStr;
+
+use rand::{
+    prelude::{SliceRandom, ThreadRng},
+    RngCore,
+};
+use serde_json::json;
+
+use crate::{
+    fileserver_api::{DEV_FILESERVER, DEV_OPEN_GROUP_SERVER},
+    loki::{self, Network, ServiceNode},
+    onions::NextHop,
+    sn_api,
+};
+
+/// File downloaded by `RequestKind::FileGet` (~5 mb)
+const TEST_FILE: &str = "qot36t";
+
+/// Stored messages don't need to live long
+const STORE_TTL_MS: u64 = 60_000;
+
+/// Message size for `store` without a size given
+const DEFAULT_STORE_SIZE: usize = 256;
+
+/// What an onion request made by the server asks for
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequestKind {
+    /// `get_snodes_for_pubkey` for a random key
+    GetSnodes,
+    /// Store a message with this many bytes of data
+    Store(usize),
+    /// Retrieve all messages of a random key
+    Retrieve,
+    /// Download `TEST_FILE` from the file server
+    FileGet,
+    /// Latest messages of an open group
+    OpenGroupPoll,
+}
+
+impl RequestKind {
+    /// How results of this kind are tagged
+    pub fn tag(&self) -> String {
+        match self {
+            RequestKind::GetSnodes => "get_snodes".to_owned(),
+            RequestKind::Store(size) => format!("store:{}", size),
+            RequestKind::Retrieve => "retrieve".to_owned(),
+            RequestKind::FileGet => "file_get".to_owned(),
+            RequestKind::OpenGroupPoll => "open_group_poll".to_owned(),
+        }
+    }
+}
+
+impl FromStr for RequestKind {
+    type Err = String;
+
+    /// One of `get_snodes`, `store[:<bytes>]`, `retrieve`, `file_get`, `open_group_poll`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+
+        let kind = parts.next().unwrap_or_default();
+
+        match (kind, parts.next()) {
+            ("get_snodes", None) => Ok(RequestKind::GetSnodes),
+            ("store", None) => Ok(RequestKind::Store(DEFAULT_STORE_SIZE)),
+            ("store", Some(size)) => size
+                .parse()
+                .map(RequestKind::Store)
+                .map_err(|_| format!("Invalid message size: {}", size)),
+            ("retrieve", None) => Ok(RequestKind::Retrieve),
+            ("file_get", None) => Ok(RequestKind::FileGet),
+            ("open_group_poll", None) => Ok(RequestKind::OpenGroupPoll),
+            _ => Err(format!("Unknown request kind: {}", s)),
+        }
+    }
+}
+
+/// Request kinds with their weights
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workload {
+    kinds: Vec<(RequestKind, u32)>,
+}
+
+impl Workload {
+    pub fn choose(&self, rng: &mut ThreadRng) -> &RequestKind {
+        &self
+            .kinds
+            .choose_weighted(rng, |(_, weight)| *weight)
+            .expect("Workload has positive weights")
+            .0
+    }
+}
+
+impl FromStr for Workload {
+    type Err = String;
+
+    /// Comma separated `<kind>=<weight>`, e.g. `get_snodes=5,store:4096=1,file_get=1`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kinds = s
+            .split(',')
+            .map(|entry| {
+                let mut parts = entry.trim().splitn(2, '=');
+
+                let kind = parts.next().unwrap_or_default().parse()?;
+
+                let weight = parts
+                    .next()
+                    .and_then(|weight| weight.parse().ok())
+                    .ok_or(format!("Expected a weight for `{}`", entry))?;
+
+                Ok((kind, weight))
+            })
+            .collect::<Result<Vec<(RequestKind, u32)>, String>>()?;
+
+        if kinds.iter().all(|(_, weight)| *weight == 0) {
+            return Err("Workload needs a positive weight".to_owned());
+        }
+
+        Ok(Workload { kinds })
+    }
+}
+
+/// Target and payload for a request of `kind`, sent to one of `nodes` if it goes to a node
+pub fn build_request(
+    kind: &RequestKind,
+    nodes: &[ServiceNode],
+    net: &Network,
+    rng: &mut ThreadRng,
+) -> (NextHop, String) {
+    let node = nodes.choose(rng).expect("Node should exist");
+
+    // Storage requests have to go to the key's swarm
+    let pk_in_swarm =
+        |rng: &mut ThreadRng| loki::PubKey::gen_in_swarm(rng, node.swarm_id, net).to_string();
+
+    match kind {
+        RequestKind::GetSnodes => {
+            let pk = loki::PubKey::gen_random(rng, net).to_string();
+
+            let payload = json!({
+                "method": "get_snodes_for_pubkey",
+                "params": {
+                    "pubKey": pk,
+                }
+            });
+
+            (NextHop::Node(node.clone()), payload.to_string())
+        }
+        RequestKind::Store(size) => {
+            let mut data = vec![0u8; *size];
+            rng.fill_bytes(&mut data);
+
+            let payload =
+                sn_api::store_request(&pk_in_swarm(rng), &base64::encode(&data), STORE_TTL_MS);
+
+            (NextHop::Node(node.clone()), payload.to_string())
+        }
+        RequestKind::Retrieve => {
+            let payload = sn_api::retrieve_request(&pk_in_swarm(rng), "");
+
+            (NextHop::Node(node.clone()), payload.to_string())
+        }
+        RequestKind::FileGet => {
+            let payload = json!({
+                "method": "GET",
+                "body": "",
+                "headers": {},
+                "endpoint": format!("loki/v1/f/{}", TEST_FILE),
+            });
+
+            (DEV_FILESERVER.next_hop(), payload.to_string())
+        }
+        RequestKind::OpenGroupPoll => {
+            let payload = json!({
+                "method": "GET",
+                "body": "",
+                "headers": {},
+                "endpoint": "channels/1/messages?count=5",
+            });
+
+            (DEV_OPEN_GROUP_SERVER.next_hop(), payload.to_string())
+        }
+    }
+}
+
+#[test]
+fn test_parse_workload() {
+    let workload: Workload = "get_snodes=5, store:4096=2,store=1,file_get=0"
+        .parse()
+        .unwrap();
+
+    assert_eq!(
+        workload.kinds,
+        vec![
+            (RequestKind::GetSnodes, 5),
+            (RequestKind::Store(4096), 2),
+            (RequestKind::Store(DEFAULT_STORE_SIZE), 1),
+            (RequestKind::FileGet, 0),
+        ]
+    );
+
+    let mut rng = rand::thread_rng();
+
+    assert!((0..100).all(|_| workload.choose(&mut rng) != &RequestKind::FileGet));
+
+    assert!("get_snodes".parse::<Workload>().is_err());
+    assert!("store:big=1".parse::<Workload>().is_err());
+    assert!("retrieve:10=1".parse::<Workload>().is_err());
+    assert!("get_snodes=0".parse::<Workload>().is_err());
+}
+
+#[tokio::test]
+async fn test_store_goes_to_the_swarm() {
+    use crate::{
+        fake_net::FakeNetwork, loki::LOCAL_NET, onions::send_onion_req, onions_core::OnionVersion,
+    };
+
+    let net = FakeNetwork::start(4, 2);
+
+    let nodes = net.service_nodes();
+
+    let mut rng = rand::thread_rng();
+
+    for target in &nodes[..4] {
+        let (next_hop, payload) = build_request(
+            &RequestKind::Store(100),
+            std::slice::from_ref(target),
+            &LOCAL_NET,
+            &mut rng,
+        );
+
+        let path = [nodes[4].clone(), nodes[5].clone(), nodes[6].clone()];
+
+        send_onion_req(
+            path,
+            next_hop,
+            payload.as_bytes(),
+            0,
+            OnionVersion::V2,
+            Default::default(),
+        )
+        .await
+        .expect("Node refused to store the message");
+    }
+}
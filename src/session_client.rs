@@ -1,9 +1,72 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{stream, Stream};
+use rand::prelude::SliceRandom;
+use serde::Deserialize;
+
 use crate::{
-    http_clients::OnionClient,
+    http_clients::{OnionClient, Request},
     loki::{self, Network, ServiceNode},
+    session_identity::SessionIdentity,
     sn_api,
 };
 
+/// Store every message on this many members of the recipient's swarm
+const STORE_TO_NODES: usize = 3;
+
+/// Messages are kept for a day
+const MESSAGE_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Poll this many members of a swarm for messages
+const POLL_NODES: usize = 3;
+
+/// Whether one swarm member accepted a request
+#[derive(Debug)]
+pub struct NodeResult {
+    pub node: ServiceNode,
+    pub result: Result<(), String>,
+}
+
+/// A message retrieved from a swarm
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub hash: String,
+    pub data: Vec<u8>,
+    /// Unix time in milliseconds
+    pub expiration: u64,
+}
+
+#[derive(Deserialize)]
+struct RetrievedMessage {
+    hash: String,
+    #[serde(default)]
+    expiration: u64,
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct RetrieveResponse {
+    messages: Vec<RetrievedMessage>,
+}
+
+/// What polling a key has learned so far
+#[derive(Default)]
+struct PollState {
+    /// Polled swarm members, topped up from the swarm when some of them fail
+    nodes: Vec<ServiceNode>,
+    /// Hash of the last message from each node (by ed25519 key)
+    last_hash: HashMap<String, String>,
+    /// Hashes of messages already seen from any node, with their expiration
+    seen: HashMap<String, u64>,
+    /// A polled node failed, so the swarm is fetched again to replace it
+    lost_nodes: bool,
+    /// New messages not handed out yet
+    pending: VecDeque<Message>,
+}
+
 pub struct SessionClient {
     onion_client: OnionClient,
     node_pool: Vec<ServiceNode>,
@@ -23,15 +86,420 @@ impl SessionClient {
         }
     }
 
-    pub async fn store_message(&self, pk: &str, message: &[u8]) {
-        // 0. Get at least one node to start with;
+    /// Store `message` for `pk` on a few members of its swarm (through onion requests),
+    /// reporting which of them accepted it. Only fails if the swarm can't be found.
+    pub async fn store_message(
+        &mut self,
+        pk: &str,
+        message: &[u8],
+    ) -> Result<Vec<NodeResult>, String> {
+        let payload = sn_api::store_request(pk, &base64::encode(message), MESSAGE_TTL_MS);
+
+        self.send_to_swarm(pk, &payload).await
+    }
+
+    /// Delete our messages with `hashes` from a few members of our swarm
+    pub async fn delete_messages(
+        &mut self,
+        identity: &SessionIdentity,
+        hashes: &[String],
+    ) -> Result<Vec<NodeResult>, String> {
+        let payload = identity.delete_request(hashes);
+
+        self.send_to_swarm(&identity.session_id(), &payload).await
+    }
+
+    /// Make our messages with `hashes` expire at `expiry` (unix time in milliseconds)
+    /// on a few members of our swarm
+    pub async fn expire_messages(
+        &mut self,
+        identity: &SessionIdentity,
+        hashes: &[String],
+        expiry: u64,
+    ) -> Result<Vec<NodeResult>, String> {
+        let payload = identity.expire_request(hashes, expiry);
+
+        self.send_to_swarm(&identity.session_id(), &payload).await
+    }
+
+    /// Send `payload` to `STORE_TO_NODES` members of `pk`'s swarm
+    async fn send_to_swarm(
+        &mut self,
+        pk: &str,
+        payload: &serde_json::Value,
+    ) -> Result<Vec<NodeResult>, String> {
+        let swarm = self.get_swarm(pk).await?;
+
+        let nodes: Vec<ServiceNode> = swarm
+            .choose_multiple(&mut rand::thread_rng(), STORE_TO_NODES)
+            .cloned()
+            .collect();
+
+        let mut results = vec![];
+
+        for node in nodes {
+            let req = Request {
+                url: node.storage_url("storage_rpc/v1"),
+                method: "POST".to_owned(),
+                body: payload.to_string(),
+            };
+
+            let result = self
+                .onion_client
+                .onion_to_node(req, node.clone())
+                .await
+                .map(|_| ());
+
+            results.push(NodeResult { node, result });
+        }
+
+        Ok(results)
+    }
+
+    /// New messages for `identity`, polling a few members of its swarm every `interval`
+    /// (starting right away). Every message is only yielded once, no matter how
+    /// many nodes returned it. The stream never ends.
+    pub fn poll_messages(
+        &mut self,
+        identity: &SessionIdentity,
+        interval: Duration,
+    ) -> impl Stream<Item = Message> + '_ {
+        let identity = identity.clone();
 
-        let rand_node = &self.node_pool[0];
+        stream::unfold(
+            (self, PollState::default(), true),
+            move |(client, mut state, first)| {
+                let identity = identity.clone();
 
-        // 1. Find the recipient
+                async move {
+                    let mut first = first;
 
-        let swarm = sn_api::get_swarm_for_pk(rand_node, pk).await;
+                    while state.pending.is_empty() {
+                        if !first {
+                            tokio::time::sleep(interval).await;
+                        }
+
+                        first = false;
+
+                        client.poll_once(&identity, &mut state).await;
+                    }
+
+                    let message = state.pending.pop_front().unwrap();
+
+                    Some((message, (client, state, first)))
+                }
+            },
+        )
+    }
+
+    /// Retrieve from every polled node once, queueing messages not seen before
+    async fn poll_once(&mut self, identity: &SessionIdentity, state: &mut PollState) {
+        if state.nodes.is_empty() || state.lost_nodes {
+            // The swarm might have changed since the nodes were picked
+            match self.get_swarm(&identity.session_id()).await {
+                Ok(swarm) => {
+                    let new: Vec<ServiceNode> = swarm
+                        .into_iter()
+                        .filter(|n| {
+                            !state
+                                .nodes
+                                .iter()
+                                .any(|polled| polled.pubkey_ed25519 == n.pubkey_ed25519)
+                        })
+                        .collect();
+
+                    let missing = POLL_NODES.saturating_sub(state.nodes.len());
+
+                    state.nodes.extend(
+                        new.choose_multiple(&mut rand::thread_rng(), missing)
+                            .cloned(),
+                    );
+
+                    state.lost_nodes = false;
+                }
+                Err(err) => eprintln!("Could not get the swarm: {}", err),
+            }
+        }
+
+        if state.nodes.is_empty() {
+            eprintln!("Could not poll: no swarm members");
+            return;
+        }
+
+        // Messages can't come back once they expired
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Could not get UNIX time")
+            .as_millis() as u64;
+
+        state.seen.retain(|_, expiration| *expiration >= now);
+
+        for node in state.nodes.clone() {
+            let last_hash = state
+                .last_hash
+                .get(&node.pubkey_ed25519)
+                .cloned()
+                .unwrap_or_default();
+
+            let messages = match self.retrieve(identity, &last_hash, &node).await {
+                Ok(messages) => messages,
+                Err(err) => {
+                    // Dead or no longer in the swarm (421), replaced on the next poll
+                    eprintln!("Could not retrieve from {}: {}", node, err);
+
+                    state
+                        .nodes
+                        .retain(|n| n.pubkey_ed25519 != node.pubkey_ed25519);
+                    state.last_hash.remove(&node.pubkey_ed25519);
+                    state.lost_nodes = true;
+
+                    continue;
+                }
+            };
+
+            if let Some(last) = messages.last() {
+                state
+                    .last_hash
+                    .insert(node.pubkey_ed25519.clone(), last.hash.clone());
+            }
+
+            for message in messages {
+                if state
+                    .seen
+                    .insert(message.hash.clone(), message.expiration)
+                    .is_none()
+                {
+                    state.pending.push_back(message);
+                }
+            }
+        }
+    }
+
+    /// Our messages that `node` has after the one with `last_hash`
+    async fn retrieve(
+        &mut self,
+        identity: &SessionIdentity,
+        last_hash: &str,
+        node: &ServiceNode,
+    ) -> Result<Vec<Message>, String> {
+        let req = Request {
+            url: node.storage_url("storage_rpc/v1"),
+            method: "POST".to_owned(),
+            body: identity.retrieve_request(last_hash).to_string(),
+        };
+
+        let res = self.onion_client.onion_to_node(req, node.clone()).await?;
+
+        let res: RetrieveResponse =
+            serde_json::from_str(&res).map_err(|err| format!("Invalid response: {}", err))?;
+
+        res.messages
+            .into_iter()
+            .map(|m| {
+                let data = base64::decode(&m.data)
+                    .map_err(|_| format!("Message {} is not base64", m.hash))?;
+
+                Ok(Message {
+                    hash: m.hash,
+                    data,
+                    expiration: m.expiration,
+                })
+            })
+            .collect()
+    }
+
+    /// Members of `pk`'s swarm, as told by a random node
+    async fn get_swarm(&self, pk: &str) -> Result<Vec<ServiceNode>, String> {
+        let node = self
+            .node_pool
+            .choose(&mut rand::thread_rng())
+            .ok_or("Node pool is empty")?;
+
+        let swarm = sn_api::get_swarm_for_pk(node, pk)
+            .await
+            .map_err(|err| format!("Could not get swarm from {}: {}", node, err))?;
+
+        if swarm.is_empty() {
+            return Err(format!("{} returned an empty swarm", node));
+        }
 
-        // dbg!(swarm);
+        Ok(swarm)
     }
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_store_message() {
+    use crate::fake_net::{FakeNetwork, FakeSeed};
+
+    let net = FakeNetwork::start(2, 4);
+
+    let seed = FakeSeed::start(0, net.fixture()).unwrap();
+
+    let network = seed.network();
+
+    let mut client = SessionClient::new(&network).await;
+
+    let pk = loki::PubKey::gen_random(&mut rand::thread_rng(), &network).to_string();
+
+    let results = client.store_message(&pk, b"hello").await.unwrap();
+
+    assert_eq!(results.len(), STORE_TO_NODES);
+    assert!(results.iter().all(|res| res.result.is_ok()));
+
+    // The nodes that accepted the message have it
+    for res in &results {
+        let node = net
+            .nodes()
+            .iter()
+            .find(|n| n.info().pubkey_ed25519 == res.node.pubkey_ed25519)
+            .unwrap();
+
+        let messages = node.messages_for(&pk);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(base64::decode(&messages[0].data).unwrap(), b"hello");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_poll_messages() {
+    use crate::fake_net::{FakeNetwork, FakeSeed};
+    use futures::StreamExt;
+
+    let net = FakeNetwork::start(2, 4);
+
+    let seed = FakeSeed::start(0, net.fixture()).unwrap();
+
+    let network = seed.network();
+
+    let mut sender = SessionClient::new(&network).await;
+    let mut receiver = SessionClient::new(&network).await;
+
+    let identity = SessionIdentity::generate(&mut rand::thread_rng(), &network);
+
+    let pk = identity.session_id();
+
+    sender.store_message(&pk, b"one").await.unwrap();
+
+    let messages = receiver.poll_messages(&identity, Duration::from_millis(100));
+
+    futures::pin_mut!(messages);
+
+    assert_eq!(messages.next().await.unwrap().data, b"one");
+
+    sender.store_message(&pk, b"two").await.unwrap();
+
+    assert_eq!(messages.next().await.unwrap().data, b"two");
+
+    // Both messages are on several nodes, but they only come once
+    let next = tokio::time::timeout(Duration::from_millis(500), messages.next()).await;
+
+    assert!(next.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_delete_and_expire() {
+    use crate::fake_net::{FakeNetwork, FakeSeed};
+
+    let net = FakeNetwork::start(1, 3);
+
+    let seed = FakeSeed::start(0, net.fixture()).unwrap();
+
+    let network = seed.network();
+
+    let mut client = SessionClient::new(&network).await;
+
+    let identity = SessionIdentity::generate(&mut rand::thread_rng(), &network);
+
+    let pk = identity.session_id();
+
+    client.store_message(&pk, b"one").await.unwrap();
+    client.store_message(&pk, b"two").await.unwrap();
+
+    let stored = net.nodes()[0].messages_for(&pk);
+
+    let hashes: Vec<String> = stored.iter().map(|m| m.hash.clone()).collect();
+
+    let expiry = stored[0].timestamp + 1000;
+
+    let results = client
+        .expire_messages(&identity, &hashes[..1], expiry)
+        .await
+        .unwrap();
+
+    assert!(results.iter().all(|res| res.result.is_ok()));
+
+    let results = client
+        .delete_messages(&identity, &hashes[1..])
+        .await
+        .unwrap();
+
+    assert!(results.iter().all(|res| res.result.is_ok()));
+
+    for node in net.nodes() {
+        let messages = node.messages_for(&pk);
+
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].hash, hashes[0]);
+        assert_eq!(messages[0].timestamp + messages[0].ttl, expiry);
+    }
+
+    // Someone else can't delete our messages
+    let other = SessionIdentity::generate(&mut rand::thread_rng(), &network);
+
+    let payload = {
+        let mut req = other.delete_request(&hashes);
+        req["params"]["pubkey"] = pk.clone().into();
+        req
+    };
+
+    let results = client.send_to_swarm(&pk, &payload).await.unwrap();
+
+    assert!(results.iter().all(|res| res.result.is_err()));
+    assert_eq!(net.nodes()[0].messages_for(&pk).len(), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_polling_replaces_failed_nodes() {
+    use crate::fake_net::{FakeNetwork, FakeSeed};
+
+    // A single swarm, so every node can be polled
+    let net = FakeNetwork::start(1, 5);
+
+    let seed = FakeSeed::start(0, net.fixture()).unwrap();
+
+    let network = seed.network();
+
+    let mut client = SessionClient::new(&network).await;
+
+    let identity = SessionIdentity::generate(&mut rand::thread_rng(), &network);
+
+    // A node that left the network, relays can't find it
+    let mut gone = net.service_nodes()[0].clone();
+    gone.pubkey_ed25519 = hex::encode([0xff; 32]);
+
+    let mut state = PollState {
+        nodes: vec![gone.clone()],
+        ..Default::default()
+    };
+
+    state.seen.insert("expired".to_owned(), 1);
+    state.seen.insert("current".to_owned(), u64::MAX);
+
+    client.poll_once(&identity, &mut state).await;
+
+    // The node is dropped and expired hashes are forgotten
+    assert!(state.nodes.is_empty());
+    assert!(state.lost_nodes);
+    assert_eq!(state.seen.keys().collect::<Vec<_>>(), ["current"]);
+
+    client.poll_once(&identity, &mut state).await;
+
+    // ... and replaced with swarm members
+    assert_eq!(state.nodes.len(), POLL_NODES);
+    assert!(!state.lost_nodes);
+    assert!(state
+        .nodes
+        .iter()
+        .all(|n| n.pubkey_ed25519 != gone.pubkey_ed25519));
+}
@@ -0,0 +1,214 @@
+//! A Session account: an ed25519 keypair, whose x25519 form (with the
+//! network's prefix) is the Session ID that messages are stored under.
+//! Storage servers only let the owner retrieve, delete or expire messages,
+//! which is checked with an ed25519 signature over the request.
+
+use std::{
+    borrow::Cow,
+    convert::TryInto,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ed25519_dalek::{Signer, SigningKey};
+use rand::RngCore;
+use serde_json::{json, Value};
+
+use crate::{loki::Network, sn_api};
+
+#[derive(Clone)]
+pub struct SessionIdentity {
+    signing_key: SigningKey,
+    /// Session ID prefix of the network the identity is for
+    prefix: Cow<'static, str>,
+}
+
+impl std::fmt::Debug for SessionIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SessionIdentity: <{}>", self.session_id())
+    }
+}
+
+impl SessionIdentity {
+    pub fn generate(rng: &mut dyn RngCore, network: &Network) -> Self {
+        let mut seed = [0u8; 32];
+        rng.fill_bytes(&mut seed);
+
+        SessionIdentity::from_seed(&seed, network)
+    }
+
+    /// The identity for a 32 byte ed25519 seed (what Session backs up as the recovery phrase)
+    pub fn from_seed(seed: &[u8; 32], network: &Network) -> Self {
+        SessionIdentity {
+            signing_key: SigningKey::from_bytes(seed),
+            prefix: network.session_id_prefix.clone(),
+        }
+    }
+
+    /// Hex ed25519 public key
+    pub fn pubkey_ed25519(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    /// The x25519 public key, converted from the ed25519 one
+    pub fn pubkey_x25519(&self) -> [u8; 32] {
+        self.signing_key.verifying_key().to_montgomery().to_bytes()
+    }
+
+    /// Network prefix followed by the hex x25519 key, e.g. `05...`
+    pub fn session_id(&self) -> String {
+        format!("{}{}", self.prefix, hex::encode(self.pubkey_x25519()))
+    }
+
+    /// Base64 ed25519 signature of `message`
+    pub fn sign(&self, message: &[u8]) -> String {
+        base64::encode(self.signing_key.sign(message).to_bytes())
+    }
+
+    /// `retrieve` request for our messages after the one with `last_hash`,
+    /// signed over `"retrieve" || timestamp`
+    pub fn retrieve_request(&self, last_hash: &str) -> Value {
+        let mut req = sn_api::retrieve_request(&self.session_id(), last_hash);
+
+        let timestamp = now_ms();
+        let signature = self.sign(format!("retrieve{}", timestamp).as_bytes());
+
+        let params = &mut req["params"];
+        params["pubkey_ed25519"] = json!(self.pubkey_ed25519());
+        params["timestamp"] = json!(timestamp);
+        params["signature"] = json!(signature);
+
+        req
+    }
+
+    /// `delete` request for our messages with `hashes`, signed over `"delete" || hashes...`
+    pub fn delete_request(&self, hashes: &[String]) -> Value {
+        let signature = self.sign(format!("delete{}", hashes.concat()).as_bytes());
+
+        json!({
+            "method": "delete",
+            "params": {
+                "pubkey": self.session_id(),
+                "pubkey_ed25519": self.pubkey_ed25519(),
+                "messages": hashes,
+                "signature": signature,
+            }
+        })
+    }
+
+    /// `expire` request setting the expiry of our messages with `hashes` to `expiry`
+    /// (unix time in milliseconds), signed over `"expire" || expiry || hashes...`
+    pub fn expire_request(&self, hashes: &[String], expiry: u64) -> Value {
+        let signature = self.sign(format!("expire{}{}", expiry, hashes.concat()).as_bytes());
+
+        json!({
+            "method": "expire",
+            "params": {
+                "pubkey": self.session_id(),
+                "pubkey_ed25519": self.pubkey_ed25519(),
+                "messages": hashes,
+                "expiry": expiry,
+                "signature": signature,
+            }
+        })
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Could not get UNIX time")
+        .as_millis() as u64
+}
+
+/// Check that `params` were signed over `message` by the owner of the Session ID `pk`,
+/// as storage servers do before touching a key's messages
+pub fn verify_request(pk: &str, params: &Value, message: &[u8]) -> Result<(), String> {
+    use ed25519_dalek::{Signature, VerifyingKey};
+
+    let pubkey: [u8; 32] = params["pubkey_ed25519"]
+        .as_str()
+        .and_then(|key| hex::decode(key).ok())
+        .and_then(|key| key.try_into().ok())
+        .ok_or("invalid `pubkey_ed25519`")?;
+
+    let pubkey = VerifyingKey::from_bytes(&pubkey).map_err(|_| "invalid `pubkey_ed25519`")?;
+
+    // The Session ID is the x25519 key after the network prefix
+    let x25519 = hex::encode(pubkey.to_montgomery().to_bytes());
+
+    if pk.len() < x25519.len() || pk[pk.len() - x25519.len()..] != x25519 {
+        return Err("`pubkey_ed25519` does not match the pubkey".to_owned());
+    }
+
+    let signature: [u8; 64] = params["signature"]
+        .as_str()
+        .and_then(|sig| base64::decode(sig).ok())
+        .and_then(|sig| sig.try_into().ok())
+        .ok_or("invalid `signature`")?;
+
+    pubkey
+        .verify_strict(message, &Signature::from_bytes(&signature))
+        .map_err(|_| "signature verification failed".to_owned())
+}
+
+#[test]
+fn test_signed_requests() {
+    use crate::loki::{MAINNET, TESTNET};
+
+    let identity = SessionIdentity::from_seed(&[7u8; 32], &MAINNET);
+
+    let session_id = identity.session_id();
+
+    assert_eq!(session_id.len(), 66);
+    assert!(session_id.starts_with("05"));
+    assert!(SessionIdentity::from_seed(&[7u8; 32], &TESTNET)
+        .session_id()
+        .ends_with(&session_id[2..]));
+
+    // Same ed25519 key as other implementations derive from the seed
+    let ed25519_from_ring = {
+        use ring::signature::KeyPair;
+
+        let keypair = ring::signature::Ed25519KeyPair::from_seed_unchecked(&[7u8; 32]).unwrap();
+        hex::encode(keypair.public_key().as_ref())
+    };
+
+    assert_eq!(identity.pubkey_ed25519(), ed25519_from_ring);
+
+    // ... and the x25519 key is that of the x25519 secret derived from the seed
+    let x25519 = {
+        use sha2::Digest;
+
+        let hash = sha2::Sha512::digest(&[7u8; 32]);
+        let secret: [u8; 32] = hash[..32].try_into().unwrap();
+
+        x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::from(secret))
+    };
+
+    assert_eq!(identity.pubkey_x25519(), x25519.to_bytes());
+
+    let req = identity.retrieve_request("");
+    let params = &req["params"];
+
+    assert_eq!(params["pubKey"], session_id);
+
+    let message = format!("retrieve{}", params["timestamp"]);
+
+    verify_request(&session_id, params, message.as_bytes()).unwrap();
+
+    // Someone else's signature, or a different message, doesn't pass
+    let other = SessionIdentity::generate(&mut rand::thread_rng(), &MAINNET);
+
+    assert!(verify_request(&other.session_id(), params, message.as_bytes()).is_err());
+    assert!(verify_request(&session_id, params, b"retrieve0").is_err());
+
+    let hashes = vec!["a".to_owned(), "b".to_owned()];
+
+    let req = identity.delete_request(&hashes);
+
+    verify_request(&session_id, &req["params"], b"deleteab").unwrap();
+
+    let req = identity.expire_request(&hashes, 1000);
+
+    verify_request(&session_id, &req["params"], b"expire1000ab").unwrap();
+}
@@ -1,19 +1,91 @@
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use hyper::client::conn::SendRequest;
 use serde_json::{json, Value};
 
 use crate::{
-    ecdh,
+    ecdh::{self, EncryptionType},
     http_clients::{ClearnetClient, HttpClient, OnionClient, Request},
     loki::{Network, ServiceNode, LOCAL_NET},
-    onions::{NextHop, OnionPath},
+    onions::{NextHop, OnionErrorKind, OnionPath, OnionResponse, OnionTiming},
 };
 
+/// Give up on the guard after this long
+const GUARD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Give up on connecting to a node being probed after this long
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub async fn onion_request_v2(
-    client: &reqwest::Client,
     path: &OnionPath,
     payload: &[u8],
-) -> Result<String, String> {
-    let (payload, decryption_key) = crate::onions_core::v2::onion_request(path, payload).await;
+    enc_types: [EncryptionType; 4],
+    timing: &mut OnionTiming,
+) -> Result<String, OnionErrorKind> {
+    let (payload, decryption_key) =
+        crate::onions_core::v2::onion_request(path, payload, enc_types, timing).await;
+
+    let res_body = send_to_guard(path, payload, timing).await?;
+
+    let time_now = Instant::now();
+
+    let res_body = String::from_utf8_lossy(&res_body).to_string();
+
+    // The target responds with the cipher it received the request with
+    let res = ecdh::decrypt(enc_types[3], res_body, &decryption_key);
+
+    timing.decryption = time_now.elapsed();
+
+    res.ok_or(OnionErrorKind::Decryption)
+}
+
+/// Unlike v2, the response is binary and comes with its own status
+pub async fn onion_request_v4(
+    path: &OnionPath,
+    payload: &[u8],
+    enc_types: [EncryptionType; 4],
+    timing: &mut OnionTiming,
+) -> Result<OnionResponse, OnionErrorKind> {
+    let (payload, decryption_key) =
+        crate::onions_core::v4::onion_request(path, payload, enc_types, timing).await?;
+
+    let res_body = send_to_guard(path, payload, timing).await?;
+
+    let time_now = Instant::now();
+
+    let plaintext = ecdh::decrypt_bytes(enc_types[3], &res_body, &decryption_key);
+
+    timing.decryption = time_now.elapsed();
+
+    let plaintext = plaintext.ok_or(OnionErrorKind::Decryption)?;
+
+    let (meta, body) = crate::onions_core::v4::parse_bencoded_pair(&plaintext)
+        .map_err(OnionErrorKind::InvalidResponse)?;
+
+    let invalid = |err: &str| OnionErrorKind::InvalidResponse(err.to_owned());
+
+    let meta: Value =
+        serde_json::from_slice(&meta).map_err(|_| invalid("Response metadata is not json"))?;
+
+    let status = meta["code"]
+        .as_u64()
+        .ok_or_else(|| invalid("No code in response metadata"))?;
 
+    Ok(OnionResponse {
+        body: String::from_utf8_lossy(&body).to_string(),
+        status: status as u32,
+    })
+}
+
+/// Returns the body of a successful response
+async fn send_to_guard(
+    path: &OnionPath,
+    payload: Vec<u8>,
+    timing: &mut OnionTiming,
+) -> Result<Vec<u8>, OnionErrorKind> {
     // Send to node 1
 
     // The first node is alays of type `Node`
@@ -22,47 +94,125 @@ pub async fn onion_request_v2(
         _ => panic!("First node must not be a server"),
     };
 
-    let target = "onion_req/v2";
+    let exchange = exchange_with_guard(first_node, payload, timing);
+
+    let (status, res_body) = tokio::time::timeout(GUARD_TIMEOUT, exchange)
+        .await
+        .map_err(|_| OnionErrorKind::GuardTransport("timed out".to_owned()))??;
+
+    if !status.is_success() {
+        return Err(OnionErrorKind::from_guard_response(
+            status.as_u16(),
+            &String::from_utf8_lossy(&res_body),
+        ));
+    }
+
+    Ok(res_body.to_vec())
+}
 
-    let url = format!(
-        "https://{}:{}/{}",
-        first_node.public_ip, first_node.storage_port, target
-    );
+/// The connection is made by hand (rather than through reqwest)
+/// so that connecting can be timed separately from the request
+async fn exchange_with_guard(
+    guard: &ServiceNode,
+    payload: Vec<u8>,
+    timing: &mut OnionTiming,
+) -> Result<(hyper::StatusCode, hyper::body::Bytes), OnionErrorKind> {
+    let transport = |e: &dyn std::fmt::Display| OnionErrorKind::GuardTransport(e.to_string());
+
+    let target = "onion_req/v2";
 
     // println!("encrypted size: {}", payload.len());
 
-    let time_now = std::time::Instant::now();
+    let time_now = Instant::now();
 
-    let res = client
-        .post(&url)
-        .body(payload)
-        .send()
-        .await
-        .map_err(|e| format!("Could not send request: {}", e))?;
+    let mut sender = connect(guard).await.map_err(|e| transport(&e))?;
+
+    timing.connect = time_now.elapsed();
+
+    let req = hyper::Request::post(format!("/{}", target))
+        .header(
+            hyper::header::HOST,
+            format!("{}:{}", guard.public_ip, guard.storage_port),
+        )
+        .body(hyper::Body::from(payload))
+        .expect("Invalid request");
+
+    let time_now = Instant::now();
+
+    let res = sender.send_request(req).await.map_err(|e| transport(&e))?;
+
+    timing.round_trip = time_now.elapsed();
 
     // println!("Request roundtrip: {}ms", time_now.elapsed().as_millis());
 
     let status = res.status();
 
-    let success = status.is_success();
+    let time_now = Instant::now();
 
-    let res_body = res
-        .text()
+    let res_body = hyper::body::to_bytes(res.into_body())
         .await
-        .map_err(|_e| "could not get response body")?;
+        .map_err(|e| transport(&e))?;
 
-    if !success {
-        return Err(format!(
-            "😵 Onion request failed: [{}] <{}>",
-            status, &res_body
-        ));
+    timing.download = time_now.elapsed();
+
+    Ok((status, res_body))
+}
+
+/// TCP (and unless the node serves plain http, TLS) connection to `node`
+async fn connect(node: &ServiceNode) -> Result<SendRequest<hyper::Body>, String> {
+    let stream = tokio::net::TcpStream::connect((node.public_ip.as_str(), node.storage_port))
+        .await
+        .map_err(|e| format!("Could not connect: {}", e))?;
+
+    if node.plain_http {
+        return handshake(stream).await;
     }
 
-    decrypt(res_body, &decryption_key).ok_or("Decryption error".to_owned())
+    // Building a connector loads the system's certificates, so all requests share one
+    static CONNECTOR: OnceLock<tokio_native_tls::TlsConnector> = OnceLock::new();
+
+    let connector = CONNECTOR.get_or_init(|| {
+        // Nodes use self-signed certificates
+        let connector = tokio_native_tls::native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()
+            .expect("building TLS connector");
+
+        tokio_native_tls::TlsConnector::from(connector)
+    });
+
+    let stream = connector
+        .connect(&node.public_ip, stream)
+        .await
+        .map_err(|e| format!("TLS handshake failed: {}", e))?;
+
+    handshake(stream).await
 }
 
-fn decrypt(ciphertext: String, key: &Vec<u8>) -> Option<String> {
-    ecdh::aes_gcm_decrypt(ciphertext, key)
+/// Only connect (including the TLS handshake), to tell nodes that can't be
+/// reached apart from nodes that fail to handle requests
+pub async fn probe_connection(node: &ServiceNode) -> Result<(), String> {
+    tokio::time::timeout(PROBE_TIMEOUT, connect(node))
+        .await
+        .map_err(|_| "Timed out connecting".to_owned())?
+        .map(|_| ())
+}
+
+async fn handshake<T>(io: T) -> Result<SendRequest<hyper::Body>, String>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, connection) = hyper::client::conn::handshake(io)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // Drives the connection, finishes once the request is done and `sender` is dropped
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+
+    Ok(sender)
 }
 
 /// This is how the Snode Entry result looks like received from SN
@@ -97,6 +247,7 @@ impl From<ServiceNodeInner> for ServiceNode {
             pubkey_x25519: sn.pubkey_x25519,
             pubkey_ed25519: sn.pubkey_ed25519,
             swarm_id: 0,
+            plain_http: false,
         }
     }
 }
@@ -105,10 +256,7 @@ pub async fn get_swarm_for_pk(
     sn: &ServiceNode,
     pk: &str,
 ) -> Result<Vec<ServiceNode>, &'static str> {
-    let url = format!(
-        "https://{}:{}/storage_rpc/v1",
-        &sn.public_ip, &sn.storage_port
-    );
+    let url = sn.storage_url("storage_rpc/v1");
 
     let params = json!({
         "method": "get_snodes_for_pubkey",
@@ -141,7 +289,99 @@ pub async fn get_swarm_for_pk(
     let nodes: Vec<ServiceNodeInner> =
         serde_json::from_value(array).map_err(|_| "Could not parse Service Node entries")?;
 
-    let nodes: Vec<ServiceNode> = nodes.into_iter().map(|sn| sn.into()).collect();
+    // Swarm members are reachable the same way as the node we asked
+    let nodes: Vec<ServiceNode> = nodes
+        .into_iter()
+        .map(|inner| ServiceNode {
+            plain_http: sn.plain_http,
+            ..inner.into()
+        })
+        .collect();
+
+    Ok(nodes)
+}
+
+/// `store` request for `data` (base64) with the proof of work done,
+/// `ttl` is in milliseconds
+pub fn store_request(pk: &str, data: &str, ttl: u64) -> Value {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+
+    let nonce = crate::proof_of_work::compute_nonce(timestamp, ttl, pk, data);
+
+    json!({
+        "method": "store",
+        "params": {
+            "pubKey": pk,
+            "ttl": ttl.to_string(),
+            "nonce": base64::encode(&nonce),
+            "timestamp": timestamp.to_string(),
+            "data": data,
+        }
+    })
+}
+
+/// `retrieve` request for messages after the one with `last_hash` (all messages if empty)
+pub fn retrieve_request(pk: &str, last_hash: &str) -> Value {
+    json!({
+        "method": "retrieve",
+        "params": {
+            "pubKey": pk,
+            "lastHash": last_hash,
+        }
+    })
+}
+
+/// The node's view of the network, through the storage server's proxy to oxend
+pub async fn get_service_nodes(sn: &ServiceNode) -> Result<Vec<ServiceNode>, String> {
+    let params = json!({
+        "method": "oxend_request",
+        "params": {
+            "endpoint": "get_service_nodes",
+            "params": {
+                "active_only": true,
+                "fields": {
+                    "public_ip": true,
+                    "storage_port": true,
+                    "storage_lmq_port": true,
+                    "service_node_pubkey": true,
+                    "operator_address": true,
+                    "pubkey_x25519": true,
+                    "pubkey_ed25519": true,
+                    "swarm_id": true,
+                },
+            },
+        }
+    });
+
+    let req = Request {
+        url: sn.storage_url("storage_rpc/v1"),
+        method: "POST".to_string(),
+        body: params.to_string(),
+    };
+
+    let res_text = ClearnetClient::new()
+        .send(req)
+        .await
+        .map_err(|err| format!("Could not contact node: {}", err))?;
+
+    let v: Value = serde_json::from_str(&res_text).map_err(|_| "body is not json".to_owned())?;
+
+    let array = v["result"]["service_node_states"].clone();
+
+    let nodes: Vec<ServiceNode> = serde_json::from_value(array)
+        .map_err(|_| "Could not parse Service Node entries".to_owned())?;
+
+    // Other nodes are reachable the same way as the node we asked
+    let nodes = nodes
+        .into_iter()
+        .map(|n| ServiceNode {
+            plain_http: sn.plain_http,
+            ..n
+        })
+        .collect();
 
     Ok(nodes)
 }
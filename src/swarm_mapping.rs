@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use futures::future::join_all;
 use rand::{
@@ -7,61 +7,270 @@ use rand::{
 };
 
 use crate::{
-    loki::{self, ServiceNode},
+    loki::{Network, ServiceNode},
+    session_identity::SessionIdentity,
     sn_api,
 };
 
-/// Maps session clients to swarms
-pub struct SwarmMapping {
-    client_pks: Vec<String>,
-    swarm_mapping: HashMap<String, Vec<ServiceNode>>,
+/// How many session clients to map
+const N_CLIENTS: usize = 100;
+
+/// Which of `swarm_ids` the storage server would assign `pk` to
+/// (the one closest to the xor of the key's u64 parts, wrapping around)
+pub fn swarm_for_pubkey(pk: &str, swarm_ids: &[u64]) -> Option<u64> {
+    // Session ids come with a `05` prefix which doesn't participate in the mapping
+    let pk = if pk.len() == 66 { &pk[2..] } else { pk };
+
+    if pk.len() != 64 || hex::decode(pk).is_err() {
+        return None;
+    }
+
+    let position = (0..4).fold(0u64, |acc, i| {
+        acc ^ u64::from_str_radix(&pk[i * 16..(i + 1) * 16], 16).unwrap()
+    });
+
+    // u64::MAX is reserved for nodes not assigned to a swarm
+    const MAX_ID: u64 = u64::MAX - 1;
+
+    let swarm_ids: Vec<u64> = swarm_ids
+        .iter()
+        .cloned()
+        .filter(|id| *id != u64::MAX)
+        .collect();
+
+    let leftmost = *swarm_ids.iter().min()?;
+    let rightmost = *swarm_ids.iter().max()?;
+
+    let mut best = swarm_ids[0];
+    let mut best_dist = MAX_ID;
+
+    for id in swarm_ids {
+        let dist = id.abs_diff(position);
+
+        if dist < best_dist {
+            best = id;
+            best_dist = dist;
+        }
+    }
+
+    if position > rightmost {
+        // Wraps when `position` is u64::MAX, as the storage server's unsigned math does
+        if MAX_ID.wrapping_sub(position).wrapping_add(leftmost) < best_dist {
+            best = leftmost;
+        }
+    } else if position < leftmost && position + (MAX_ID - rightmost) < best_dist {
+        best = rightmost;
+    }
+
+    Some(best)
+}
+
+/// A node's answer to `get_snodes_for_pubkey`, next to our own mapping
+#[derive(Debug)]
+pub struct SwarmAnswer {
+    pub node: ServiceNode,
+    pub pk: String,
+    pub expected: u64,
+    /// The swarm of the returned nodes, `None` if they aren't all in one swarm we know
+    pub answer: Result<Option<u64>, String>,
 }
 
-async fn inner_task(node: &ServiceNode, pk: String) -> (String, Vec<ServiceNode>) {
-    let nodes = sn_api::get_swarm_for_pk(node, &pk.to_owned())
-        .await
-        .expect("Could not get pk for node");
+impl SwarmAnswer {
+    /// The node answered, but not with the swarm the key belongs to
+    pub fn is_wrong(&self) -> bool {
+        matches!(self.answer, Ok(answer) if answer != Some(self.expected))
+    }
+}
 
-    (pk, nodes)
+/// Maps session clients to swarms
+pub struct SwarmMapping {
+    clients: Vec<SessionIdentity>,
+    /// Every node we know of, by swarm
+    swarms: HashMap<u64, Vec<ServiceNode>>,
 }
 
 impl SwarmMapping {
-    pub async fn init(node: &ServiceNode) -> Self {
+    /// Clients mapped to the swarms of `nodes` the same way storage servers do it,
+    /// so no node has to be asked
+    pub fn new(nodes: &[ServiceNode], net: &Network) -> Self {
         let mut rng = StdRng::seed_from_u64(0);
 
-        let n: usize = 100;
+        let clients = (0..N_CLIENTS)
+            .map(|_| SessionIdentity::generate(&mut rng, net))
+            .collect();
+
+        let mut swarms: HashMap<u64, Vec<ServiceNode>> = HashMap::new();
+
+        for node in nodes {
+            swarms.entry(node.swarm_id).or_default().push(node.clone());
+        }
+
+        SwarmMapping { clients, swarms }
+    }
+
+    /// The swarm `pk` belongs to
+    pub fn swarm_for(&self, pk: &str) -> Option<u64> {
+        let swarm_ids: Vec<u64> = self.swarms.keys().cloned().collect();
+
+        swarm_for_pubkey(pk, &swarm_ids)
+    }
+
+    pub fn get_one(&self) -> (SessionIdentity, Vec<ServiceNode>) {
+        let mut rng = thread_rng();
+
+        let client = self.clients.choose(&mut rng).unwrap().to_owned();
+
+        let swarm_id = self
+            .swarm_for(&client.session_id())
+            .expect("No swarms to map to");
+
+        (client, self.swarms[&swarm_id].clone())
+    }
+
+    /// Ask each of `nodes` for the swarm of a random client, so nodes with a
+    /// different view of the network (or a different mapping) stand out
+    pub async fn verify(&self, nodes: &[ServiceNode]) -> Vec<SwarmAnswer> {
+        let tasks = nodes.iter().map(|node| {
+            let client = self.clients.choose(&mut thread_rng()).unwrap();
+
+            self.ask(node, client.session_id())
+        });
+
+        join_all(tasks).await
+    }
 
-        let mut tasks = vec![];
+    async fn ask(&self, node: &ServiceNode, pk: String) -> SwarmAnswer {
+        let expected = self.swarm_for(&pk).expect("No swarms to map to");
 
-        for i in 0..n {
-            let pk = loki::PubKey::gen_random(&mut rng, &loki::MAINNET).to_string();
+        let answer = sn_api::get_swarm_for_pk(node, &pk)
+            .await
+            .map(|members| self.swarm_of(&members))
+            .map_err(|err| err.to_owned());
 
-            let task = inner_task(&node, pk.to_owned());
-            tasks.push(task);
+        SwarmAnswer {
+            node: node.clone(),
+            pk,
+            expected,
+            answer,
         }
+    }
+
+    /// The one swarm the `members` we know of are in (our pool can lag behind
+    /// the node's, so unknown members are fine)
+    fn swarm_of(&self, members: &[ServiceNode]) -> Option<u64> {
+        let keys: HashSet<&str> = members.iter().map(|n| n.pubkey_ed25519.as_str()).collect();
 
-        let res = join_all(tasks).await;
+        let swarm_ids: HashSet<u64> = self
+            .swarms
+            .values()
+            .flatten()
+            .filter(|n| keys.contains(n.pubkey_ed25519.as_str()))
+            .map(|n| n.swarm_id)
+            .collect();
 
-        let mut client_pks = Vec::new();
-        let mut swarm_mapping = HashMap::new();
-        for (pk, nodes) in res {
-            client_pks.push(pk.clone());
-            swarm_mapping.insert(pk, nodes);
+        match swarm_ids.len() {
+            1 => swarm_ids.into_iter().next(),
+            _ => None,
         }
+    }
+}
 
-        SwarmMapping {
-            client_pks,
-            swarm_mapping,
+pub fn print_answers(answers: &[SwarmAnswer]) {
+    for res in answers {
+        match &res.answer {
+            Err(err) => println!("{}: {}", res.node, err),
+            Ok(answer) if res.is_wrong() => println!(
+                "{}: wrong swarm for {}, expected {}, got {}",
+                res.node,
+                res.pk,
+                res.expected,
+                answer.map_or("unknown nodes".to_owned(), |id| id.to_string())
+            ),
+            Ok(_) => {}
         }
     }
 
-    pub fn get_one(&self) -> (String, Vec<ServiceNode>) {
-        let mut rng = thread_rng();
+    let answered = answers.iter().filter(|res| res.answer.is_ok()).count();
+    let wrong = answers.iter().filter(|res| res.is_wrong()).count();
+
+    println!(
+        "Swarms: {}/{} nodes answered, {} of them with the wrong swarm",
+        answered,
+        answers.len(),
+        wrong
+    );
+}
+
+#[test]
+fn test_swarm_for_pubkey() {
+    let pk = |position: u64| format!("05{:048x}{:016x}", 0, position);
 
-        let pk = self.client_pks.choose(&mut rng).unwrap().to_owned();
+    let ids = [100, 200, 300];
 
-        let nodes = self.swarm_mapping.get(&pk).unwrap();
+    assert_eq!(swarm_for_pubkey(&pk(0), &[]), None);
+    assert_eq!(swarm_for_pubkey("05abc", &ids), None);
 
-        (pk, nodes.to_owned())
+    assert_eq!(swarm_for_pubkey(&pk(100), &ids), Some(100));
+    assert_eq!(swarm_for_pubkey(&pk(140), &ids), Some(100));
+    assert_eq!(swarm_for_pubkey(&pk(160), &ids), Some(200));
+    // Closer to 100 going around than to 300
+    assert_eq!(swarm_for_pubkey(&pk(u64::MAX - 10), &ids), Some(100));
+    assert_eq!(swarm_for_pubkey(&pk(u64::MAX / 2), &ids), Some(300));
+    assert_eq!(swarm_for_pubkey(&pk(u64::MAX), &ids), Some(100));
+    // The unassigned swarm id never gets keys
+    assert_eq!(swarm_for_pubkey(&pk(u64::MAX - 1), &[1, u64::MAX]), Some(1));
+
+    // All four parts count, the prefix doesn't
+    let key = format!("{:016x}{:016x}{:016x}{:016x}", 1, 2, 4, 200 ^ 7);
+    assert_eq!(swarm_for_pubkey(&key, &ids), Some(200));
+    assert_eq!(swarm_for_pubkey(&format!("05{}", key), &ids), Some(200));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_verify_mapping() {
+    use crate::{
+        fake_net::{FakeNetwork, Fault},
+        loki::LOCAL_NET,
+    };
+
+    let net = FakeNetwork::start(4, 2);
+
+    let nodes = net.service_nodes();
+
+    net.nodes()[0].set_faults(vec![Fault::WrongSwarm]);
+    net.nodes()[1].set_faults(vec![Fault::DropConnection]);
+
+    let mapping = SwarmMapping::new(&nodes, &LOCAL_NET);
+
+    // Our mapping agrees with the nodes for every client
+    for client in &mapping.clients {
+        let res = mapping.ask(&nodes[2], client.session_id()).await;
+
+        assert_eq!(res.answer, Ok(Some(res.expected)));
     }
+
+    let (client, swarm) = mapping.get_one();
+
+    assert!(swarm
+        .iter()
+        .all(|n| Some(n.swarm_id) == mapping.swarm_for(&client.session_id())));
+
+    let answers = mapping.verify(&nodes).await;
+
+    assert!(answers[1].answer.is_err());
+    assert!(answers[2..].iter().all(|res| !res.is_wrong()));
+
+    // The faulty node claims keys for its own swarm
+    let wrong = mapping
+        .clients
+        .iter()
+        .map(|client| mapping.ask(&nodes[0], client.session_id()))
+        .collect::<Vec<_>>();
+
+    let wrong = join_all(wrong).await;
+
+    assert!(wrong.iter().any(|res| res.is_wrong()));
+    assert!(wrong
+        .iter()
+        .all(|res| res.answer == Ok(Some(nodes[0].swarm_id))));
 }
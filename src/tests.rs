@@ -1,5 +1,5 @@
 use std::{
-    convert::TryInto,
+    collections::HashMap,
     sync::Arc,
     time::{Duration, SystemTime},
 };
@@ -13,17 +13,22 @@ use serde_json::json;
 use parking_lot::Mutex;
 
 use crate::{
+    ecdh::EncryptionType,
     fileserver_api,
     fileserver_api::DEV_FILESERVER,
+    inference::{self, Observation},
     loki::{self, Network},
     loki::{LokiServer, ServiceNode},
-    node_pool::NodePool,
+    node_pool::{NodePool, PathConstraints},
     onions::NextHop,
-    onions::{send_onion_req, OnionPath},
+    onions::{send_onion_req_timed, OnionError, OnionPath, OnionTiming},
+    onions_core::OnionVersion,
+    path_manager::PathManager,
     session_server_client::FileServerInterface,
     session_server_client::{OpenGroupInterface, SessionServerClient},
     sn_api,
-    swarm_mapping::SwarmMapping,
+    swarm_mapping::{self, SwarmMapping},
+    triage::{self, Triage},
 };
 
 fn sleep_ms(millis: u64) {
@@ -44,32 +49,11 @@ fn target_message(rng: &mut StdRng, network: &Network) -> String {
 }
 
 fn store_message(pk: &str) -> String {
-    let timestamp = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-        * 1000;
-
     let ttl: u64 = 60_000; // ms
 
-    let data = "TODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODO";
-
-    let nonce = crate::proof_of_work::compute_nonce(timestamp, ttl, &pk, &data);
-
-    let params = json!({
-        "method": "store",
-        "params": {
-            "pubKey": &pk,
-            "ttl": format!("{}", ttl),
-            "nonce": &base64::encode(&nonce),
-            "timestamp": format!("{}", timestamp),
-            "data": data,
-        }
-    });
-
-    let payload = params.to_string();
+    let data = "TODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODOTODO";
 
-    payload
+    sn_api::store_request(pk, data, ttl).to_string()
 }
 
 fn get_file(file: &str) -> String {
@@ -159,13 +143,12 @@ async fn test_onion_path(context: Arc<Mutex<TestContext>>, idx: u64) {
     println!("test onion path: {}", idx);
     // let mut context_lock = context.lock();
 
-    let mut nodes = context.lock().node_pool.get_random_nodes(4);
+    let (client, nodes) = context.lock().swarm_mapping.get_one();
 
-    nodes.pop().unwrap();
-
-    let path: [_; 3] = nodes.try_into().unwrap();
-
-    let (pk, nodes) = context.lock().swarm_mapping.get_one();
+    let (version, enc_types, network) = {
+        let context = context.lock();
+        (context.version, context.enc_types, context.network.clone())
+    };
 
     // let mut rng = rand::thread_rng();
 
@@ -189,7 +172,25 @@ async fn test_onion_path(context: Arc<Mutex<TestContext>>, idx: u64) {
         NextHop::Node(target)
     };
 
-    let payload = store_message(&pk);
+    let path: [_; 3] = {
+        let mut context = context.lock();
+        let context = &mut *context;
+
+        let path = match &mut context.path_manager {
+            Some(path_manager) => path_manager.get_path(&mut context.node_pool, &target),
+            None => context.node_pool.get_path_for_target(&target),
+        };
+
+        match path {
+            Ok(path) => path,
+            Err(err) => {
+                eprintln!("[{}] {}", idx, err);
+                return;
+            }
+        }
+    };
+
+    let payload = store_message(&client.session_id());
 
     // // let file = "005yfe"; // smallets file (no problem)
     // // let file = "we2c37"; // smaller 86% failure rate
@@ -202,20 +203,58 @@ async fn test_onion_path(context: Arc<Mutex<TestContext>>, idx: u64) {
 
     let payload = payload.as_bytes();
 
-    let res = send_onion_req(path, target, payload, idx).await;
+    let onion_path = OnionPath {
+        node_1: NextHop::Node(path[0].clone()),
+        node_2: NextHop::Node(path[1].clone()),
+        node_3: NextHop::Node(path[2].clone()),
+        target: target.clone(),
+    };
+
+    let (res, timing) =
+        send_onion_req_timed(path.clone(), target, payload, idx, version, enc_types).await;
+
+    {
+        let mut context = context.lock();
+
+        match &res {
+            Ok(_) => context.node_pool.report_success(&onion_path),
+            Err(err) => context.node_pool.report_failure(&err.path, err.culprit()),
+        }
+
+        if let Some(path_manager) = &mut context.path_manager {
+            match &res {
+                Ok(_) => path_manager.report_success(&path),
+                Err(err) => path_manager.report_failure(&path, err),
+            }
+        }
+    }
 
     let res = match res {
         Ok(res) => OnionTestResult {
             success: true,
             time: time_now.elapsed(),
-            path: None,
+            timing,
+            path: onion_path,
+            error: None,
+            triage: None,
         },
         Err(onion_err) => {
-            eprintln!("[{}] error: {}", idx, onion_err.message);
+            eprintln!("[{}] error: {}", idx, onion_err);
+
+            let time = time_now.elapsed();
+
+            let triage = triage::triage(&onion_err.path, &network).await;
+
+            println!("[{}] probes of the failed path:", idx);
+            triage.print();
+
             OnionTestResult {
                 success: false,
-                time: time_now.elapsed(),
-                path: Some(onion_err.path),
+                time,
+                timing,
+                path: onion_path,
+                error: Some(onion_err),
+                triage: Some(triage),
             }
         }
     };
@@ -224,10 +263,14 @@ async fn test_onion_path(context: Arc<Mutex<TestContext>>, idx: u64) {
 }
 
 #[derive(Debug)]
-struct OnionTestResult {
+pub struct OnionTestResult {
     pub success: bool,
     pub time: std::time::Duration,
-    path: Option<OnionPath>,
+    pub timing: OnionTiming,
+    pub path: OnionPath,
+    error: Option<OnionError>,
+    /// Probes of the failed path's nodes and links
+    pub triage: Option<Triage>,
 }
 
 struct TestContext {
@@ -235,13 +278,22 @@ struct TestContext {
     results: Vec<OnionTestResult>,
     network: Network,
     swarm_mapping: SwarmMapping,
+    version: OnionVersion,
+    enc_types: [EncryptionType; 4],
+    /// Pick paths at random if not set
+    path_manager: Option<PathManager>,
 }
 
-pub async fn test_onion_requests() {
+pub async fn test_onion_requests(
+    net: &Network,
+    version: OnionVersion,
+    enc_types: [EncryptionType; 4],
+    path_manager: Option<PathManager>,
+    path_constraints: PathConstraints,
+    refresh_sources: Option<usize>,
+) -> Vec<OnionTestResult> {
     // Make n onion requests selecting nodes randomly
 
-    let net = &loki::MAINNET;
-
     let n = 50;
     const N_PARALLEL: usize = 50;
 
@@ -250,13 +302,25 @@ pub async fn test_onion_requests() {
 
     let mut node_pool = NodePool::init(net).await;
 
+    // Only keep the seed's nodes that nodes in the pool agree on
+    if let Some(n) = refresh_sources {
+        if let Err(err) = node_pool.refresh_from_nodes(net, n).await {
+            eprintln!("Could not refresh nodes from the pool: {}", err);
+        }
+    }
+
+    node_pool.set_path_constraints(path_constraints);
+
     // node_pool.remove_non_foundation();
 
     // node_pool.truncate(50);
 
-    let node = &node_pool.get_random_nodes(1)[0];
+    let clients = SwarmMapping::new(node_pool.get_all_nodes(), net);
+
+    // A few live answers to check the mapping against
+    let nodes = node_pool.get_random_nodes(5);
 
-    let clients = SwarmMapping::init(node).await;
+    swarm_mapping::print_answers(&clients.verify(&nodes).await);
 
     println!("Session clients are initialized");
 
@@ -265,6 +329,9 @@ pub async fn test_onion_requests() {
         results: vec![],
         network: net.to_owned(),
         swarm_mapping: clients,
+        version,
+        enc_types,
+        path_manager,
     };
 
     let context = Arc::new(Mutex::new(context));
@@ -328,68 +395,50 @@ pub async fn test_onion_requests() {
 
     print_stats(context.clone());
 
-    // test_individual_nodes(context.clone()).await;
-}
-
-async fn test_node(node: &NextHop, network: &Network) {
-    if let NextHop::Node(node) = node {
-        let mut rng = StdRng::seed_from_u64(0);
+    context.lock().node_pool.print_scoreboard(20);
 
-        let pk = loki::PubKey::gen_random(&mut rng, network).to_string();
-
-        let res = sn_api::get_swarm_for_pk(node, &pk).await;
-
-        match res {
-            Ok(_res) => {
-                println!("{}: OK", node);
-            }
-            Err(err) => {
-                println!("{}: {}", node, err);
-            }
+    // Keep what we learned about nodes for the next run
+    if let Some(path) = &net.snapshot_file {
+        if let Err(err) = context
+            .lock()
+            .node_pool
+            .save(std::path::Path::new(path.as_ref()))
+        {
+            eprintln!("{}", err);
         }
     }
-}
-
-async fn test_individual_nodes(context: Arc<Mutex<TestContext>>) {
-    let context = context.lock();
 
-    let failed_results = context.results.iter().filter(|res| !res.success);
-
-    for res in failed_results {
-        let path = res.path.as_ref().expect("No path on error");
-        let nodes = [&path.node_1, &path.node_2, &path.node_3, &path.target];
-
-        println!(
-            "Failed path: [{}]->[{}]->[{}]->[{}]",
-            &nodes[0], &nodes[1], &nodes[2], &nodes[3]
-        );
-
-        for node in &nodes {
-            test_node(node, &context.network).await;
+    if let Some(path_manager) = &context.lock().path_manager {
+        for guard in path_manager.guards() {
+            println!("Guard in use: {}", guard);
         }
     }
-}
 
-fn print_stats(context: Arc<Mutex<TestContext>>) {
-    use std::collections::HashMap;
+    let results = std::mem::take(&mut context.lock().results);
 
-    let mut failed_nodes = HashMap::<String, u32>::new();
-
-    let context = context.lock();
+    results
+}
 
-    dbg!(&context.results);
+/// How many failures each node is blamed for, in ascending order. When the error
+/// names the failing hop only that node is blamed, otherwise every node on the path is.
+/// Nodes are identified by ed25519 keys, as that is all we know about targets.
+fn failed_node_counts(results: &[OnionTestResult]) -> Vec<(String, u32)> {
+    let mut failed_nodes = HashMap::<String, u32>::new();
 
-    for res in &context.results {
+    for res in results {
         if !res.success {
-            let path = res.path.as_ref().expect("No path on error");
-            let nodes = [&path.node_1, &path.node_2, &path.node_3, &path.target];
+            let err = res.error.as_ref().expect("No error on failure");
+            let path = &err.path;
+
+            let nodes = match err.culprit() {
+                Some(culprit) => vec![culprit],
+                None => vec![&path.node_1, &path.node_2, &path.node_3, &path.target],
+            };
 
             for n in &nodes {
                 match n {
                     NextHop::Node(n) => {
-                        let entry = failed_nodes
-                            .entry(n.service_node_pubkey.clone())
-                            .or_insert(0);
+                        let entry = failed_nodes.entry(n.pubkey_ed25519.clone()).or_insert(0);
                         *entry += 1;
                     }
                     _ => {}
@@ -405,7 +454,15 @@ fn print_stats(context: Arc<Mutex<TestContext>>) {
 
     failed_nodes.sort_by(|a, b| a.1.cmp(&b.1));
 
-    for (key, failures) in failed_nodes {
+    failed_nodes
+}
+
+fn print_stats(context: Arc<Mutex<TestContext>>) {
+    let context = context.lock();
+
+    dbg!(&context.results);
+
+    for (key, failures) in failed_node_counts(&context.results) {
         println!("{}: {}", key, failures);
     }
 
@@ -414,4 +471,179 @@ fn print_stats(context: Arc<Mutex<TestContext>>) {
     let average_ms = total_duration / context.results.len() as u128;
 
     println!("Average: {} ms", average_ms);
+
+    print_timing_percentiles(&context.results);
+
+    print_verdicts(&context.results);
+
+    inference::print_estimates(&inference::estimate(&observations(&context.results)), 20);
+}
+
+/// What the probes blamed failed paths on, most common first
+fn print_verdicts(results: &[OnionTestResult]) {
+    let mut counts = HashMap::<String, u32>::new();
+
+    for triage in results.iter().filter_map(|res| res.triage.as_ref()) {
+        *counts.entry(triage.verdict().to_string()).or_insert(0) += 1;
+    }
+
+    let mut counts: Vec<_> = counts.into_iter().collect();
+
+    counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+    for (verdict, count) in counts {
+        println!("{:>5} {}", count, verdict);
+    }
+}
+
+/// Results in the form the bad node estimator takes
+pub fn observations(results: &[OnionTestResult]) -> Vec<Observation> {
+    results
+        .iter()
+        .map(|res| {
+            let path = &res.path;
+
+            let nodes = [&path.node_1, &path.node_2, &path.node_3, &path.target]
+                .iter()
+                .filter_map(|hop| match hop {
+                    NextHop::Node(n) => Some(n.pubkey_ed25519.clone()),
+                    _ => None,
+                })
+                .collect();
+
+            Observation {
+                nodes,
+                success: res.success,
+            }
+        })
+        .collect()
+}
+
+/// Value below which `p` percent of `sorted` fall (nearest rank)
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::default();
+    }
+
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// p50/p90/p99 of each part of successful requests, to tell whether
+/// slow requests are slow on the client, in the handshake or in the path
+fn print_timing_percentiles(results: &[OnionTestResult]) {
+    let timings: Vec<&OnionTiming> = results
+        .iter()
+        .filter(|res| res.success)
+        .map(|res| &res.timing)
+        .collect();
+
+    if timings.is_empty() {
+        return;
+    }
+
+    println!(
+        "{:<16} {:>10} {:>10} {:>10}",
+        "timing (ms)", "p50", "p90", "p99"
+    );
+
+    let print_row = |name: &str, mut values: Vec<Duration>| {
+        values.sort();
+
+        let ms = |p| percentile(&values, p).as_secs_f64() * 1000.0;
+
+        println!(
+            "{:<16} {:>10.3} {:>10.3} {:>10.3}",
+            name,
+            ms(50.0),
+            ms(90.0),
+            ms(99.0)
+        );
+    };
+
+    for (idx, name) in OnionTiming::COMPONENTS.iter().enumerate() {
+        print_row(name, timings.iter().map(|t| t.components()[idx]).collect());
+    }
+
+    print_row("total", timings.iter().map(|t| t.total()).collect());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_failure_attribution() {
+    use crate::{
+        fake_net::{FakeNetwork, FakeSeed, Fault},
+        triage::{Check, Verdict},
+    };
+
+    let net = FakeNetwork::start(3, 4);
+
+    // Swarms are mapped locally, so any node can be the bad one
+    let bad_node = &net.nodes()[0];
+
+    bad_node.set_faults(vec![Fault::Status(502)]);
+
+    let bad_key = bad_node.info().pubkey_ed25519;
+
+    let seed = FakeSeed::start(0, net.fixture()).unwrap();
+
+    let network = seed.network();
+
+    let results = test_onion_requests(
+        &network,
+        OnionVersion::V2,
+        Default::default(),
+        None,
+        Default::default(),
+        None,
+    )
+    .await;
+
+    assert!(results.iter().any(|res| !res.success));
+
+    // Successful requests went through every step
+    for res in results.iter().filter(|res| res.success) {
+        assert!(res
+            .timing
+            .components()
+            .iter()
+            .all(|t| *t > Duration::default()));
+    }
+
+    // Every failed path contains the bad node, so it is blamed for every failure
+    let failed = results.iter().filter(|res| !res.success).count() as u32;
+
+    let counts = failed_node_counts(&results);
+    let (_, failures) = counts.iter().find(|(key, _)| key == &bad_key).unwrap();
+
+    assert!(*failures >= failed);
+
+    // Nodes that shared paths with the bad node are blamed by counting too, the
+    // estimates tell them apart by a wide margin
+    let estimates = inference::estimate(&observations(&results));
+
+    assert_eq!(estimates[0].pubkey, bad_key);
+    assert!(estimates[0].failure_prob > 0.9);
+    assert!(estimates[1..].iter().all(|e| e.failure_prob < 0.1));
+
+    // The bad node accepts connections, but fails every request
+    let expected = Verdict::Node {
+        pubkey: bad_key,
+        check: Check::Swarm,
+    };
+
+    for res in results.iter().filter(|res| !res.success) {
+        assert_eq!(res.triage.as_ref().unwrap().verdict(), expected);
+    }
+}
+
+#[test]
+fn test_percentile() {
+    let values: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+
+    assert_eq!(percentile(&values, 50.0), Duration::from_millis(50));
+    assert_eq!(percentile(&values, 99.0), Duration::from_millis(99));
+    assert_eq!(percentile(&values, 100.0), Duration::from_millis(100));
+    assert_eq!(percentile(&values[..1], 1.0), Duration::from_millis(1));
+    assert_eq!(percentile(&[], 50.0), Duration::default());
 }
@@ -0,0 +1,283 @@
+//! Probing the nodes of a failed path one by one, to tell which node
+//! or which link between two nodes broke it
+
+use std::fmt;
+
+use futures::future::join_all;
+use serde_json::json;
+
+use crate::{
+    loki::{self, Network, ServiceNode},
+    onions::{send_onion_req, NextHop, OnionPath},
+    onions_core::OnionVersion,
+    sn_api,
+};
+
+/// Checks a node has to pass on its own, in the order they are made
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Check {
+    /// Connecting and the TLS handshake
+    Connect,
+    /// `get_snodes_for_pubkey` over clearnet
+    Swarm,
+    /// An onion request where the node is every hop
+    SelfOnion,
+}
+
+impl Check {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Check::Connect => "connect",
+            Check::Swarm => "swarm",
+            Check::SelfOnion => "self_onion",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeProbe {
+    /// Ed25519 key
+    pub pubkey: String,
+    /// The first check the node failed and why
+    pub failure: Option<(Check, String)>,
+}
+
+/// An onion request from one node of the path straight to the next one
+#[derive(Debug, Clone)]
+pub struct LinkProbe {
+    /// Ed25519 keys
+    pub from: String,
+    pub to: String,
+    pub error: Option<String>,
+}
+
+/// What the probes say broke the path
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    /// The node failed a check on its own
+    Node { pubkey: String, check: Check },
+    /// Both nodes work, but the first one can't relay to the second
+    Link { from: String, to: String },
+    /// Every probe passed, the failure might have been transient
+    Inconclusive,
+}
+
+impl Verdict {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Verdict::Node { .. } => "node",
+            Verdict::Link { .. } => "link",
+            Verdict::Inconclusive => "inconclusive",
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::Node { pubkey, check } => {
+                write!(f, "node {} failed check: {}", pubkey, check.name())
+            }
+            Verdict::Link { from, to } => write!(f, "link {} -> {} is broken", from, to),
+            Verdict::Inconclusive => write!(f, "all probes passed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Triage {
+    /// In path order, servers are not probed
+    pub nodes: Vec<NodeProbe>,
+    /// Only links between nodes that passed their own checks are probed
+    pub links: Vec<LinkProbe>,
+}
+
+impl Triage {
+    /// The first node along the path that failed on its own, otherwise the first broken link
+    pub fn verdict(&self) -> Verdict {
+        if let Some(node) = self.nodes.iter().find(|n| n.failure.is_some()) {
+            let (check, _) = node.failure.as_ref().unwrap();
+
+            return Verdict::Node {
+                pubkey: node.pubkey.clone(),
+                check: *check,
+            };
+        }
+
+        match self.links.iter().find(|l| l.error.is_some()) {
+            Some(link) => Verdict::Link {
+                from: link.from.clone(),
+                to: link.to.clone(),
+            },
+            None => Verdict::Inconclusive,
+        }
+    }
+
+    pub fn print(&self) {
+        for node in &self.nodes {
+            match &node.failure {
+                Some((check, err)) => {
+                    println!("  {}: {} failed: {}", node.pubkey, check.name(), err)
+                }
+                None => println!("  {}: OK", node.pubkey),
+            }
+        }
+
+        for link in &self.links {
+            match &link.error {
+                Some(err) => println!("  {} -> {}: {}", link.from, link.to, err),
+                None => println!("  {} -> {}: OK", link.from, link.to),
+            }
+        }
+
+        println!("  Verdict: {}", self.verdict());
+    }
+}
+
+/// Probe every node on `path` directly, then every link between two nodes that work
+pub async fn triage(path: &OnionPath, net: &Network) -> Triage {
+    let hops = [&path.node_1, &path.node_2, &path.node_3, &path.target];
+
+    let nodes: Vec<&ServiceNode> = hops
+        .iter()
+        .filter_map(|hop| match hop {
+            NextHop::Node(n) => Some(n),
+            _ => None,
+        })
+        .collect();
+
+    let failures = join_all(nodes.iter().map(|n| probe_node(n, net))).await;
+
+    let probes: Vec<NodeProbe> = nodes
+        .iter()
+        .zip(failures)
+        .map(|(n, failure)| NodeProbe {
+            pubkey: n.pubkey_ed25519.clone(),
+            failure,
+        })
+        .collect();
+
+    let works = |n: &ServiceNode| {
+        probes
+            .iter()
+            .any(|p| p.pubkey == n.pubkey_ed25519 && p.failure.is_none())
+    };
+
+    let links: Vec<(&ServiceNode, &ServiceNode)> = hops
+        .windows(2)
+        .filter_map(|pair| match (pair[0], pair[1]) {
+            (NextHop::Node(from), NextHop::Node(to)) if works(from) && works(to) => {
+                Some((from, to))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let errors = join_all(links.iter().map(|(from, to)| probe_link(from, to, net))).await;
+
+    let links = links
+        .iter()
+        .zip(errors)
+        .map(|((from, to), res)| LinkProbe {
+            from: from.pubkey_ed25519.clone(),
+            to: to.pubkey_ed25519.clone(),
+            error: res.err(),
+        })
+        .collect();
+
+    Triage {
+        nodes: probes,
+        links,
+    }
+}
+
+fn random_pubkey(net: &Network) -> String {
+    loki::PubKey::gen_random(&mut rand::thread_rng(), net).to_string()
+}
+
+async fn probe_node(node: &ServiceNode, net: &Network) -> Option<(Check, String)> {
+    if let Err(err) = sn_api::probe_connection(node).await {
+        return Some((Check::Connect, err));
+    }
+
+    let pk = random_pubkey(net);
+
+    if let Err(err) = sn_api::get_swarm_for_pk(node, &pk).await {
+        return Some((Check::Swarm, err.to_owned()));
+    }
+
+    probe_link(node, node, net)
+        .await
+        .err()
+        .map(|err| (Check::SelfOnion, err))
+}
+
+/// An onion request where `from` relays to itself until the last layer, so
+/// `from -> to` is the only link between different nodes it crosses
+pub async fn probe_link(from: &ServiceNode, to: &ServiceNode, net: &Network) -> Result<(), String> {
+    let payload = json!({
+        "method": "get_snodes_for_pubkey",
+        "params": {
+            "pubKey": random_pubkey(net),
+        }
+    })
+    .to_string();
+
+    let path = [from.clone(), from.clone(), from.clone()];
+
+    send_onion_req(
+        path,
+        NextHop::Node(to.clone()),
+        payload.as_bytes(),
+        0,
+        OnionVersion::V2,
+        Default::default(),
+    )
+    .await
+    .map(|_| ())
+    .map_err(|err| err.to_string())
+}
+
+#[tokio::test]
+async fn test_triage_finds_broken_link() {
+    use crate::fake_net::{FakeNetwork, Fault};
+
+    let net = FakeNetwork::start(1, 4);
+
+    let nodes = net.service_nodes();
+
+    let path = OnionPath {
+        node_1: NextHop::Node(nodes[0].clone()),
+        node_2: NextHop::Node(nodes[1].clone()),
+        node_3: NextHop::Node(nodes[2].clone()),
+        target: NextHop::Node(nodes[3].clone()),
+    };
+
+    net.nodes()[1].set_faults(vec![Fault::RefuseRelayTo(nodes[2].pubkey_ed25519.clone())]);
+
+    let res = triage(&path, &loki::LOCAL_NET).await;
+
+    assert!(res.nodes.iter().all(|n| n.failure.is_none()));
+    assert_eq!(res.links.len(), 3);
+    assert_eq!(
+        res.verdict(),
+        Verdict::Link {
+            from: nodes[1].pubkey_ed25519.clone(),
+            to: nodes[2].pubkey_ed25519.clone(),
+        }
+    );
+
+    // A node that is down is blamed over the links it is on
+    net.nodes()[2].set_faults(vec![Fault::DropConnection]);
+
+    let res = triage(&path, &loki::LOCAL_NET).await;
+
+    assert_eq!(res.links.len(), 1);
+    assert_eq!(
+        res.verdict(),
+        Verdict::Node {
+            pubkey: nodes[2].pubkey_ed25519.clone(),
+            check: Check::Connect,
+        }
+    );
+}